@@ -0,0 +1,187 @@
+use crate::ApiErrors;
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderMap, Method},
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    future::{ready, Future, Ready},
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+const KEY_LENGTH: usize = 32;
+// Owners of the api are kept apart from the chat ids of the telegram users that own jobs
+const OWNER_PREFIX: &str = "api:";
+
+/// The owner of the API key a request was made with. Handlers that take it reject anonymous
+/// requests
+#[derive(Debug, Clone)]
+pub struct Owner(pub String);
+
+impl FromRequest for Owner {
+    type Error = ApiErrors;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            request
+                .extensions()
+                .get::<Owner>()
+                .cloned()
+                .ok_or(ApiErrors::Unauthorized),
+        )
+    }
+}
+
+/// Owners by the sha256 of their API key. only the hashes are stored, so a leaked file doesn't
+/// leak the keys
+#[derive(Default)]
+pub struct ApiKeys {
+    owners: HashMap<String, String>,
+}
+
+impl ApiKeys {
+    pub fn hash(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    pub fn generate() -> String {
+        rand::thread_rng().sample_iter(&Alphanumeric).take(KEY_LENGTH).collect()
+    }
+
+    // Every line is `<owner> <sha256 of the key in hex>`. empty lines and lines that start with #
+    // are skipped
+    pub fn load<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut keys = ApiKeys::default();
+
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(owner), Some(hash), None) if hash.len() == 64 && hex::decode(hash).is_ok() => {
+                    keys.insert(owner, hash);
+                }
+                _ => {
+                    let error = format!("Invalid API key on line {}", number + 1);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    pub fn insert(&mut self, owner: &str, hash: &str) {
+        self.owners
+            .insert(hash.to_lowercase(), format!("{}{}", OWNER_PREFIX, owner));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+
+    // None when the request has no key at all
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Owner>, ApiErrors> {
+        let value = match headers.get(header::AUTHORIZATION) {
+            Some(value) => value.to_str().map_err(|_| ApiErrors::Unauthorized)?,
+            None => return Ok(None),
+        };
+        let key = value.strip_prefix("Bearer ").ok_or(ApiErrors::Unauthorized)?;
+
+        match self.owners.get(&Self::hash(key.trim())) {
+            Some(owner) => Ok(Some(Owner(owner.clone()))),
+            None => Err(ApiErrors::Unauthorized),
+        }
+    }
+}
+
+/// Resolves the API key of the request to its owner. Requests with an invalid key are rejected,
+/// and so are requests without one unless anonymous requests are allowed
+pub struct Authentication {
+    keys: Arc<ApiKeys>,
+    allow_anonymous: bool,
+}
+
+impl Authentication {
+    pub fn new(keys: Arc<ApiKeys>, allow_anonymous: bool) -> Self {
+        Self { keys, allow_anonymous }
+    }
+}
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service,
+            keys: Arc::clone(&self.keys),
+            allow_anonymous: self.allow_anonymous,
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: S,
+    keys: Arc<ApiKeys>,
+    allow_anonymous: bool,
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        // Browsers send preflight requests without credentials
+        if request.method() == Method::OPTIONS {
+            return Box::pin(self.service.call(request));
+        }
+
+        match self.keys.authenticate(request.headers()) {
+            Ok(Some(owner)) => {
+                request.extensions_mut().insert(owner);
+            }
+            Ok(None) if self.allow_anonymous => {}
+            Ok(None) => return Box::pin(ready(Ok(request.error_response(ApiErrors::Unauthorized)))),
+            Err(error) => return Box::pin(ready(Ok(request.error_response(error)))),
+        }
+
+        Box::pin(self.service.call(request))
+    }
+}
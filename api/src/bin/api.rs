@@ -1,16 +1,82 @@
-use actix_web::http::Method;
 use actix_web::{web, App, HttpServer};
-use log::error;
+use api::auth::{ApiKeys, Authentication};
+use api::cors::{Cors, CorsConfig};
+use api::idempotency::IdempotencyKeys;
+use api::limits::{Limits, Plan, RateLimit};
+use api::urls::UrlPolicy;
+use api::validation::Localize;
+use broker::{Broker, Exchanges};
+use log::{error, warn};
 use parking_lot::Mutex;
 use std::env;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     pretty_env_logger::init();
 
+    // api generate-key <owner> prints a new key and the line to add to API_KEYS_FILE for it
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        match (args[0].as_str(), args.get(1)) {
+            ("generate-key", Some(owner)) => {
+                let key = ApiKeys::generate();
+                println!("Key: {}", key);
+                println!("{} {}", owner, ApiKeys::hash(&key));
+                return Ok(());
+            }
+            _ => {
+                eprintln!("Usage: api [generate-key <owner>]");
+                std::process::exit(2);
+            }
+        }
+    }
+
     let api_host = env::var("API_HOST").expect("Can't find API_HOST env variable");
     let rabbit_host = env::var("RABBIT_HOST").expect("Can't find RABBIT_HOST env variable");
+    let keys = match env::var("API_KEYS_FILE") {
+        Ok(path) => match ApiKeys::load(&path) {
+            Ok(keys) => keys,
+            Err(error) => {
+                error!("api.ApiKeys.load. {}", error);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => ApiKeys::default(),
+    };
+    if keys.is_empty() {
+        warn!("No API keys are configured. the jobs resource will reject every request");
+    }
+    let keys = Arc::new(keys);
+    // Anonymous requests can still create jobs unless turned off
+    let allow_anonymous = match env::var("ALLOW_ANONYMOUS") {
+        Ok(allow) => allow.parse::<bool>().expect("ALLOW_ANONYMOUS must be true or false"),
+        Err(_) => true,
+    };
+    // Serves Swagger UI for the OpenAPI document at /docs
+    let docs = match env::var("DOCS_UI") {
+        Ok(docs) => docs.parse::<bool>().expect("DOCS_UI must be true or false"),
+        Err(_) => false,
+    };
+
+    let cors = cors_config();
+    let mut policy = UrlPolicy::default();
+    if let Some(domains) = list("URL_ALLOWED_DOMAINS") {
+        policy.allowed_domains = domains;
+    }
+    if let Some(domains) = list("URL_DENIED_DOMAINS") {
+        policy.denied_domains = domains;
+    }
+    let policy = web::Data::new(policy);
+    let limits = web::Data::new(Limits::new(plan("ANONYMOUS_"), plan("")));
+    // How long retries with the same Idempotency-Key get the job that was created
+    let idempotency_keys = match positive::<u64>("IDEMPOTENCY_TTL_SECONDS") {
+        Some(ttl) => IdempotencyKeys::new(Duration::from_secs(ttl)),
+        None => IdempotencyKeys::default(),
+    };
+    let idempotency_keys = web::Data::new(idempotency_keys);
 
     let broker = match broker::Rabbit::new(&rabbit_host).await {
         Ok(broker) => broker,
@@ -19,17 +85,114 @@ async fn main() -> std::io::Result<()> {
             std::process::exit(1);
         }
     };
+
+    // Responses of the scheduler to the requests of the jobs resource, and of the scraper to test scrapes
+    let responses = match broker.subscribe(Exchanges::Api).await {
+        Ok(consumer) => consumer.into_inner(),
+        Err(error) => {
+            error!("api.broker.subscribe. {}", error);
+            std::process::exit(1);
+        }
+    };
+    let requests = web::Data::new(api::Requests::default());
+    let listener = requests.clone();
+    actix_rt::spawn(async move { listener.listen(responses).await });
+
     let broker = Arc::new(Mutex::new(broker));
 
     HttpServer::new(move || {
         App::new()
+            .wrap(Localize)
+            .wrap(Cors::new(cors.clone()))
             .data(api::AppState {
                 broker: Arc::clone(&broker),
             })
-            .route("/create", web::post().to(api::create_handler::<broker::Rabbit>))
-            .route("/create", web::method(Method::OPTIONS).to(api::create_options))
+            .app_data(requests.clone())
+            .app_data(limits.clone())
+            .app_data(policy.clone())
+            .app_data(idempotency_keys.clone())
+            // The description of the api is public, even when anonymous requests aren't allowed
+            .route("/openapi.json", web::get().to(api::openapi::openapi_handler))
+            .configure(|cfg| {
+                if docs {
+                    cfg.route("/docs", web::get().to(api::openapi::docs_handler));
+                }
+            })
+            .service(
+                web::scope("")
+                    .wrap(RateLimit::new(limits.clone().into_inner()))
+                    // Runs before the rate limit, which depends on the owner it finds
+                    .wrap(Authentication::new(Arc::clone(&keys), allow_anonymous))
+                    .route("/create", web::post().to(api::create_handler::<broker::Rabbit>))
+                    .route("/renew", web::post().to(api::renew_handler::<broker::Rabbit>))
+                    .route("/jobs", web::get().to(api::list_jobs_handler::<broker::Rabbit>))
+                    .route("/jobs/test", web::post().to(api::test_job_handler::<broker::Rabbit>))
+                    .route("/jobs/{id}", web::get().to(api::get_job_handler::<broker::Rabbit>))
+                    .route("/jobs/{id}", web::patch().to(api::update_job_handler::<broker::Rabbit>))
+                    .route(
+                        "/jobs/{id}",
+                        web::delete().to(api::delete_job_handler::<broker::Rabbit>),
+                    )
+                    .route("/jobs/{id}/run", web::post().to(api::run_job_handler::<broker::Rabbit>)),
+            )
     })
     .bind(api_host)?
     .run()
     .await
 }
+
+// CORS_ORIGINS, CORS_METHODS and CORS_HEADERS are comma separated lists
+fn cors_config() -> CorsConfig {
+    let mut config = CorsConfig::default();
+    if let Some(origins) = list("CORS_ORIGINS") {
+        config.origins = origins;
+    }
+    if let Some(methods) = list("CORS_METHODS") {
+        config.methods = methods;
+    }
+    if let Some(headers) = list("CORS_HEADERS") {
+        config.headers = headers;
+    }
+    if let Ok(credentials) = env::var("CORS_CREDENTIALS") {
+        config.credentials = credentials
+            .parse::<bool>()
+            .expect("CORS_CREDENTIALS must be true or false");
+    }
+    if let Ok(max_age) = env::var("CORS_MAX_AGE") {
+        config.max_age = max_age.parse::<u64>().expect("CORS_MAX_AGE must be a number");
+    }
+
+    config
+}
+
+fn list(name: &str) -> Option<Vec<String>> {
+    let value = env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+    )
+}
+
+// The plan of the clients whose env variables start with prefix. e.g. ANONYMOUS_RATE_LIMIT is the
+// requests per minute of anonymous clients. variables that aren't set don't limit them
+fn plan(prefix: &str) -> Plan {
+    Plan {
+        rate: positive(&format!("{}RATE_LIMIT", prefix)),
+        max_jobs: positive(&format!("{}MAX_JOBS", prefix)),
+        min_interval: positive(&format!("{}MIN_INTERVAL_SECONDS", prefix)),
+    }
+}
+
+fn positive<T>(name: &str) -> Option<T>
+where
+    T: FromStr + PartialOrd + Default,
+{
+    let value = env::var(name).ok()?;
+    match value.parse::<T>() {
+        Ok(value) if value > T::default() => Some(value),
+        _ => panic!("{} must be a positive number", name),
+    }
+}
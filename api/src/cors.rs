@@ -0,0 +1,197 @@
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderMap, HeaderValue, Method},
+    Error, HttpResponse,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+const DEFAULT_MAX_AGE: u64 = 86_400; // Day in seconds
+
+/// Which cross origin requests browsers are allowed to make. origins are either exact, * for any
+/// origin, or patterns with a single * such as https://*.example.com
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec![String::from("http://localhost:3000")],
+            methods: ["GET", "POST", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            headers: ["content-type", "authorization", "idempotency-key"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            credentials: false,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+impl CorsConfig {
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|pattern| matches_origin(pattern, origin))
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+}
+
+// A * in the pattern stands for one or more characters of the host
+fn matches_origin(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    let mut parts = pattern.splitn(2, '*');
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+                && !origin[prefix.len()..origin.len() - suffix.len()].contains('/')
+        }
+        _ => pattern == origin,
+    }
+}
+
+/// Answers preflight requests and adds the CORS headers to every other response, errors
+/// included. has to be wrapped last, so it sees the responses of the other middleware
+pub struct Cors {
+    config: Rc<CorsConfig>,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Self {
+        Self {
+            config: Rc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorsMiddleware {
+            service,
+            config: Rc::clone(&self.config),
+        }))
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    config: Rc<CorsConfig>,
+}
+
+impl<S> CorsMiddleware<S> {
+    // The allowed origin of the request. None for requests that aren't cross origin, and for
+    // origins that aren't allowed, which browsers then block
+    fn origin(&self, request: &ServiceRequest) -> Option<HeaderValue> {
+        let origin = request.headers().get(header::ORIGIN)?;
+        if self.config.allows(origin.to_str().ok()?) {
+            return Some(origin.clone());
+        }
+
+        None
+    }
+
+    fn preflight(&self, origin: Option<HeaderValue>, method: Option<&HeaderValue>) -> HttpResponse {
+        let mut response = HttpResponse::NoContent();
+        response.header(header::VARY, "Origin");
+
+        let method = method.and_then(|method| method.to_str().ok());
+        if let (Some(origin), Some(method)) = (origin, method) {
+            if self.config.allows_method(method) {
+                response
+                    .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                    .header(header::ACCESS_CONTROL_ALLOW_METHODS, self.config.methods.join(", "))
+                    .header(header::ACCESS_CONTROL_ALLOW_HEADERS, self.config.headers.join(", "))
+                    .header(header::ACCESS_CONTROL_MAX_AGE, self.config.max_age.to_string());
+                if self.config.credentials {
+                    response.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+                }
+            }
+        }
+
+        response.finish()
+    }
+}
+
+// The origin is echoed back instead of *, since * can't be used with credentials
+fn add_headers(headers: &mut HeaderMap, origin: HeaderValue, credentials: bool) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("retry-after, idempotent-replayed"),
+    );
+    if credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let origin = self.origin(&request);
+
+        let method = request.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if request.method() == Method::OPTIONS && method.is_some() {
+            let response = self.preflight(origin, method);
+            return Box::pin(ready(Ok(request.into_response(response.into_body()))));
+        }
+
+        let credentials = self.config.credentials;
+        let response = self.service.call(request);
+        Box::pin(async move {
+            let mut response = response.await?;
+            if let Some(origin) = origin {
+                add_headers(response.headers_mut(), origin, credentials);
+            }
+
+            Ok(response)
+        })
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    validation::{invalid, ErrorCode},
+    ApiErrors,
+};
+use actix_web::{dev::Payload, web, Error, FromRequest, HttpRequest};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    time::{Duration, Instant},
+};
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+// Set on responses that were stored for an earlier request with the same key
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+pub(crate) const MAX_KEY_LENGTH: usize = 255;
+const DEFAULT_TTL: Duration = Duration::from_secs(86_400); // Day
+
+// Expired keys are dropped once there are this many
+const MAX_KEYS: usize = 10_000;
+
+/// The Idempotency-Key header of the request, if it has one, and the keys it is checked against.
+/// the keys are taken from the app data
+pub struct Idempotency {
+    key: Option<String>,
+    keys: web::Data<IdempotencyKeys>,
+}
+
+impl Idempotency {
+    // None when the request has no key. scope keeps the keys of different owners apart
+    pub fn begin(&self, scope: &str, fingerprint: String) -> Result<Option<Begin<'_>>, ApiErrors> {
+        match &self.key {
+            Some(key) => self.keys.begin(scope, key, fingerprint).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl FromRequest for Idempotency {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let keys = match web::Data::<IdempotencyKeys>::from_request(request, payload).into_inner() {
+            Ok(keys) => keys,
+            Err(error) => return ready(Err(error)),
+        };
+        let key = match request.headers().get(IDEMPOTENCY_KEY) {
+            Some(key) => key,
+            None => return ready(Ok(Self { key: None, keys })),
+        };
+
+        // Visible ASCII only, which to_str doesn't check
+        let valid =
+            !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.as_bytes().iter().all(|byte| byte.is_ascii_graphic());
+        match key.to_str() {
+            Ok(key) if valid => ready(Ok(Self {
+                key: Some(key.to_string()),
+                keys,
+            })),
+            _ => ready(Err(invalid("idempotency_key", ErrorCode::InvalidIdempotencyKey).into())),
+        }
+    }
+}
+
+// Identifies the request a key was used with. parts are separated, so ("ab", "c") and ("a", "bc")
+// differ
+pub fn fingerprint(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+/// What a create request with the key responded with
+#[derive(Debug, Clone)]
+pub struct Created {
+    pub id: String,
+    pub code: String,
+}
+
+enum State {
+    InProgress,
+    Done(Created),
+}
+
+struct Entry {
+    fingerprint: String,
+    state: State,
+    expires_at: Instant,
+}
+
+pub enum Begin<'a> {
+    // The request was already made, this is its response
+    Replay(Created),
+    Started(Reservation<'a>),
+}
+
+/// Responses of the requests made with an idempotency key, until the key expires. they are kept in
+/// memory, so a retry that reaches another api instance is made again
+pub struct IdempotencyKeys {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Default for IdempotencyKeys {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Reserves the key for the request, unless it was already used
+    fn begin(&self, scope: &str, key: &str, fingerprint: String) -> Result<Begin<'_>, ApiErrors> {
+        let now = Instant::now();
+        let entry_key = format!("{} {}", scope, key);
+        let mut entries = self.entries.lock();
+
+        if entries.len() >= MAX_KEYS {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+
+        match entries.get(&entry_key) {
+            Some(entry) if entry.expires_at <= now => {}
+            Some(entry) if entry.fingerprint != fingerprint => {
+                return Err(invalid("idempotency_key", ErrorCode::IdempotencyKeyReused));
+            }
+            Some(Entry {
+                state: State::Done(created),
+                ..
+            }) => return Ok(Begin::Replay(created.clone())),
+            Some(Entry {
+                state: State::InProgress,
+                ..
+            }) => return Err(ApiErrors::InProgress),
+            None => {}
+        }
+
+        entries.insert(
+            entry_key.clone(),
+            Entry {
+                fingerprint,
+                state: State::InProgress,
+                expires_at: now + self.ttl,
+            },
+        );
+
+        Ok(Begin::Started(Reservation {
+            keys: self,
+            entry_key,
+            completed: false,
+        }))
+    }
+}
+
+/// A key held by the request that is being made with it. the key is released if the request fails,
+/// so it can be retried
+pub struct Reservation<'a> {
+    keys: &'a IdempotencyKeys,
+    entry_key: String,
+    completed: bool,
+}
+
+impl Reservation<'_> {
+    // Stores the response for the retries of the request
+    pub fn complete(mut self, created: Created) {
+        if let Some(entry) = self.keys.entries.lock().get_mut(&self.entry_key) {
+            entry.state = State::Done(created);
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.keys.entries.lock().remove(&self.entry_key);
+        }
+    }
+}
@@ -1,28 +1,86 @@
 use actix_web::{self, body::Body, dev, error, http::StatusCode, web, HttpResponse};
-use broker::{Broker, BrokerErrors, Exchanges, Messages};
+use auth::Owner;
+use broker::{Broker, BrokerErrors, Exchanges, Job, JobError, JobReply, JobResult, Messages, ScrapeResult};
+use idempotency::{Begin, Created, Idempotency, IDEMPOTENT_REPLAYED};
+use limits::Limits;
 use parking_lot::Mutex;
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fmt, ops::RangeInclusive, sync::Arc};
+use std::{collections::HashMap, error::Error, fmt, ops::RangeInclusive, sync::Arc, time::Duration};
+use tokio::sync::oneshot;
+use tokio_stream::{Stream, StreamExt};
+use urls::UrlPolicy;
+use utoipa::{IntoParams, ToSchema};
+use validation::{invalid, ErrorCode, Errors, FieldError};
+
+pub mod auth;
+pub mod cors;
+pub mod idempotency;
+pub mod limits;
+pub mod openapi;
+pub mod scripts;
+pub mod urls;
+pub mod validation;
 
 const MIN_INTERVAL: u64 = 5;
 const MAX_INTERVAL: u64 = 604_800; // Week in seconds
 const INTERVAL_RANGE: RangeInclusive<u64> = MIN_INTERVAL..=MAX_INTERVAL;
+const MIN_TTL: u64 = 3_600; // Hour in seconds
+const MAX_TTL: u64 = 31_536_000; // Year in seconds
+const TTL_RANGE: RangeInclusive<u64> = MIN_TTL..=MAX_TTL;
+const DEFAULT_TTL: u64 = 2_628_000; // Month in seconds
+const ACTIVATION_CODE_LENGTH: usize = 8;
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+// How long a request waits for the scheduler to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a test scrape waits for the scraper. it loads the page in a new browser
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub const INVALID_INTERVAL: &str = "Interval must be in range 5-604,800 (week in seconds) and a multiple of 5";
 pub const INVALID_URL: &str = "URL must not be empty and should be valid";
+pub const INVALID_SCHEME: &str = "URL must start with http:// or https://";
+pub const PRIVATE_ADDRESS: &str = "URL must not point to a private, loopback or link-local address";
+pub const BLOCKED_DOMAIN: &str = "URL domain is not allowed";
+pub const UNRESOLVED_HOST: &str = "URL host can't be resolved";
 pub const INVALID_SCRIPT: &str = "Script can't be empty";
+pub const SCRIPT_TOO_LONG: &str = "Script must be at most 10,000 bytes";
+pub const INVALID_SCRIPT_SYNTAX: &str = "Script must be valid JavaScript";
+pub const DENIED_SCRIPT_API: &str = "Script must not use network, storage, eval or import APIs";
+pub const INVALID_TTL: &str = "TTL must be in range 3,600-31,536,000 (year in seconds), or 0 to never expire";
+pub const INVALID_ID: &str = "ID must be a valid UUID";
+pub const INVALID_LIMIT: &str = "Limit must be in range 1-100";
+pub const EMPTY_UPDATE: &str = "At least one of url, script or interval must be given";
+pub const INVALID_IDEMPOTENCY_KEY: &str = "Idempotency-Key must be 1-255 visible ASCII characters";
+pub const IDEMPOTENCY_KEY_REUSED: &str = "Idempotency-Key was already used with a different request";
 
 #[derive(Debug)]
 pub enum ApiErrors {
     Server(BrokerErrors),
-    Validation(Vec<&'static str>),
+    Validation(Vec<FieldError>),
+    // The API key is missing or unknown
+    Unauthorized,
+    NotFound,
+    Forbidden,
+    Conflict,
+    // The scheduler didn't respond in time
+    Timeout,
+    // The scraper didn't finish a test scrape in time
+    ScrapeTimeout,
+    // The client has to wait this long before its next request
+    RateLimited(Duration),
+    QuotaExceeded(String),
+    // A request with the same idempotency key hasn't finished yet
+    InProgress,
+    // The job can't be renewed before it's activated
+    NotActivated,
 }
 
 impl std::error::Error for ApiErrors {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             Self::Server(error) => Some(error),
-            Self::Validation(_) => None,
+            _ => None,
         }
     }
 }
@@ -32,24 +90,62 @@ impl fmt::Display for ApiErrors {
         match self {
             Self::Server(error) => write!(f, "Internal server error. {}", error),
             Self::Validation(errors) => {
-                let err = errors.join("\n");
+                let err = errors.iter().map(|error| error.message).collect::<Vec<_>>().join("\n");
                 f.write_str(&err)
             }
+            Self::Unauthorized => write!(f, "A valid API key is required"),
+            Self::NotFound => write!(f, "Job doesn't exist"),
+            Self::Forbidden => write!(f, "Job belongs to someone else"),
+            Self::Conflict => write!(f, "Job was changed by someone else at the same time. try again."),
+            Self::Timeout => write!(f, "Scheduler didn't respond. try again."),
+            Self::ScrapeTimeout => write!(f, "Page didn't load or the script didn't finish in time"),
+            Self::RateLimited(retry_after) => write!(
+                f,
+                "Too many requests. try again in {} seconds.",
+                retry_after_seconds(*retry_after)
+            ),
+            Self::QuotaExceeded(error) => f.write_str(error),
+            Self::InProgress => write!(f, "A request with the same Idempotency-Key is in progress. try again."),
+            Self::NotActivated => write!(f, "Job has to be activated with its code first"),
         }
     }
 }
 
+// Retry-After only takes whole seconds. rounding down would send clients back too early
+fn retry_after_seconds(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
 impl From<BrokerErrors> for ApiErrors {
     fn from(error: BrokerErrors) -> Self {
         Self::Server(error)
     }
 }
 
+impl From<JobError> for ApiErrors {
+    fn from(error: JobError) -> Self {
+        match error {
+            JobError::NotFound => Self::NotFound,
+            JobError::Forbidden => Self::Forbidden,
+            JobError::Conflict => Self::Conflict,
+            JobError::NotActivated => Self::NotActivated,
+            JobError::Internal(error) => Self::Server(BrokerErrors::Custom(error)),
+        }
+    }
+}
+
 impl error::ResponseError for ApiErrors {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::Server(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::Conflict | Self::InProgress | Self::NotActivated => StatusCode::CONFLICT,
+            Self::Timeout | Self::ScrapeTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::QuotaExceeded(_) => StatusCode::FORBIDDEN,
         }
     }
 
@@ -60,20 +156,42 @@ impl error::ResponseError for ApiErrors {
             Self::Server(_) => {
                 res = CreateResponse {
                     id: None,
+                    code: None,
                     error: Some(String::from("Internal server error. try again.")),
+                    errors: None,
                 }
             }
             Self::Validation(errors) => {
+                let messages = errors.iter().map(|error| error.message).collect::<Vec<_>>();
                 res = CreateResponse {
                     id: None,
-                    error: Some(errors.join(". ")),
+                    code: None,
+                    error: Some(messages.join(". ")),
+                    errors: Some(errors.clone()),
                 }
             }
+            error => {
+                res = CreateResponse {
+                    id: None,
+                    code: None,
+                    error: Some(error.to_string()),
+                    errors: None,
+                }
+            }
+        }
+
+        let mut response = dev::HttpResponseBuilder::new(self.status_code());
+        match self {
+            Self::Unauthorized => {
+                response.header("WWW-Authenticate", "Bearer");
+            }
+            Self::RateLimited(retry_after) => {
+                response.header("Retry-After", retry_after_seconds(*retry_after).to_string());
+            }
+            _ => {}
         }
 
-        dev::HttpResponseBuilder::new(self.status_code())
-            .header("Access-Control-Allow-Origin", "http://localhost:3000")
-            .json(res)
+        response.json(res)
     }
 }
 
@@ -83,47 +201,180 @@ pub trait Validate {
     fn validate(&self) -> Result<(), Self::Error>;
 }
 
-#[derive(Deserialize)]
+fn default_ttl() -> u64 {
+    DEFAULT_TTL
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct CreateRequest {
     url: String,
     interval: u64,
     script: String,
+    // 0 means that the job never expires
+    #[serde(default = "default_ttl")]
+    ttl: u64,
 }
 
 impl Validate for CreateRequest {
     type Error = ApiErrors;
 
     fn validate(&self) -> Result<(), Self::Error> {
-        let mut errors = Vec::new();
+        let mut errors = Errors::default();
 
-        // Validation URL is problematic. if the URL is invalid, so be it. the scraper will simply
-        // fail
-        if self.url.is_empty() {
-            errors.push(INVALID_URL)
-        }
+        // Domains are checked against the UrlPolicy once the request is valid, since that needs
+        // the network
+        errors
+            .check_result("url", urls::parse(&self.url))
+            .check("interval", valid_interval(self.interval), ErrorCode::InvalidInterval)
+            .check_result("script", scripts::check(&self.script))
+            .check(
+                "ttl",
+                self.ttl == 0 || TTL_RANGE.contains(&self.ttl),
+                ErrorCode::InvalidTtl,
+            );
 
-        if self.interval % 5 != 0 || !INTERVAL_RANGE.contains(&self.interval) {
-            errors.push(INVALID_INTERVAL)
-        }
+        errors.finish()
+    }
+}
 
-        if self.script.is_empty() {
-            errors.push(INVALID_SCRIPT)
-        }
+#[derive(Deserialize, ToSchema)]
+pub struct RenewRequest {
+    id: String,
+}
+
+impl Validate for RenewRequest {
+    type Error = ApiErrors;
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        validate_id(&self.id)
+    }
+}
+
+fn validate_id(id: &str) -> Result<(), ApiErrors> {
+    if uuid::Uuid::parse_str(id).is_err() {
+        return Err(invalid("id", ErrorCode::InvalidId));
+    }
+
+    Ok(())
+}
+
+fn valid_interval(interval: u64) -> bool {
+    interval % 5 == 0 && INTERVAL_RANGE.contains(&interval)
+}
+
+fn default_limit() -> usize {
+    DEFAULT_LIMIT
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    cursor: Option<String>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+impl Validate for ListQuery {
+    type Error = ApiErrors;
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        let mut errors = Errors::default();
+        errors.check(
+            "limit",
+            self.limit != 0 && self.limit <= MAX_LIMIT,
+            ErrorCode::InvalidLimit,
+        );
+
+        errors.finish()
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateRequest {
+    url: Option<String>,
+    interval: Option<u64>,
+    script: Option<String>,
+}
+
+impl Validate for UpdateRequest {
+    type Error = ApiErrors;
+
+    // The fields that are given follow the rules of CreateRequest
+    fn validate(&self) -> Result<(), Self::Error> {
+        let mut errors = Errors::default();
+        let empty = self.url.is_none() && self.interval.is_none() && self.script.is_none();
 
-        if !errors.is_empty() {
-            return Err(ApiErrors::Validation(errors));
+        errors.check("body", !empty, ErrorCode::EmptyUpdate);
+        if let Some(url) = &self.url {
+            errors.check_result("url", urls::parse(url));
+        }
+        if let Some(interval) = self.interval {
+            errors.check("interval", valid_interval(interval), ErrorCode::InvalidInterval);
         }
+        if let Some(script) = &self.script {
+            errors.check_result("script", scripts::check(script));
+        }
+
+        errors.finish()
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TestJobRequest {
+    url: String,
+    script: String,
+}
+
+impl Validate for TestJobRequest {
+    type Error = ApiErrors;
+
+    fn validate(&self) -> Result<(), Self::Error> {
+        let mut errors = Errors::default();
+        errors
+            .check_result("url", urls::parse(&self.url))
+            .check_result("script", scripts::check(&self.script));
 
-        Ok(())
+        errors.finish()
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+struct JobsResponse {
+    jobs: Vec<Job>,
+    // Passed as the cursor of the next request to get the following page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
 struct CreateResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<String>,
+    // Passed to the bot's /start command to activate the job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    // The same errors as error, by field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<Vec<FieldError>>,
+}
+
+// The script ran when error is None, value is what it returned then
+#[derive(Serialize, ToSchema)]
+struct TestJobResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn activation_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ACTIVATION_CODE_LENGTH)
+        .collect()
 }
 
 pub struct AppState<T>
@@ -133,9 +384,155 @@ where
     pub broker: Arc<Mutex<T>>,
 }
 
+type Pending<R> = Mutex<HashMap<String, oneshot::Sender<R>>>;
+
+/// Requests sent to the scheduler and the scraper that wait for their response, by their request id
+#[derive(Default)]
+pub struct Requests {
+    pending: Pending<JobResult>,
+    scrapes: Pending<ScrapeResult>,
+}
+
+impl Requests {
+    // Sends the request built with a new request id and waits for the response with the same id
+    pub async fn send<T, F>(&self, broker: &Mutex<T>, request: F) -> Result<JobReply, ApiErrors>
+    where
+        T: Broker,
+        F: FnOnce(String) -> Messages,
+    {
+        let result = wait(
+            &self.pending,
+            broker,
+            Exchanges::Scheduler,
+            request,
+            REQUEST_TIMEOUT,
+            ApiErrors::Timeout,
+        )
+        .await?;
+
+        Ok(result?)
+    }
+
+    // Runs the script on the scraper once and waits for its result. nothing is stored
+    pub async fn test_scrape<T>(
+        &self,
+        broker: &Mutex<T>,
+        url: String,
+        script: String,
+    ) -> Result<ScrapeResult, ApiErrors>
+    where
+        T: Broker,
+    {
+        let request = |request_id| Messages::TestScrape {
+            request_id,
+            url,
+            script,
+        };
+
+        wait(
+            &self.scrapes,
+            broker,
+            Exchanges::Scraper,
+            request,
+            SCRAPE_TIMEOUT,
+            ApiErrors::ScrapeTimeout,
+        )
+        .await
+    }
+
+    // Passes a response to the request waiting for it. Returns false if nothing waits for it,
+    // because it was sent by another instance of the api or it timed out
+    pub fn resolve(&self, request_id: &str, result: JobResult) -> bool {
+        match self.pending.lock().remove(request_id) {
+            Some(sender) => sender.send(result).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn resolve_scrape(&self, request_id: &str, result: ScrapeResult) -> bool {
+        match self.scrapes.lock().remove(request_id) {
+            Some(sender) => sender.send(result).is_ok(),
+            None => false,
+        }
+    }
+
+    pub async fn listen<S>(&self, mut messages: S)
+    where
+        S: Stream<Item = Messages> + Unpin,
+    {
+        while let Some(message) = messages.next().await {
+            match message {
+                Messages::JobResponse { request_id, result } => {
+                    self.resolve(&request_id, result);
+                }
+                Messages::TestScrapeResponse { request_id, result } => {
+                    self.resolve_scrape(&request_id, result);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Publishes the request built with a new request id and waits for the response with the same id
+async fn wait<T, F, R>(
+    pending: &Pending<R>,
+    broker: &Mutex<T>,
+    exchange: Exchanges,
+    request: F,
+    timeout: Duration,
+    timeout_error: ApiErrors,
+) -> Result<R, ApiErrors>
+where
+    T: Broker,
+    F: FnOnce(String) -> Messages,
+{
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let (sender, receiver) = oneshot::channel();
+    pending.lock().insert(request_id.clone(), sender);
+
+    let published = broker.lock().publish(exchange, request(request_id.clone())).await;
+    if let Err(error) = published {
+        pending.lock().remove(&request_id);
+        return Err(error.into());
+    }
+
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(result)) => Ok(result),
+        _ => {
+            pending.lock().remove(&request_id);
+            Err(timeout_error)
+        }
+    }
+}
+
+// Jobs created with an API key belong to its owner. the others are owned by the first chat that activates them.
+// retries with the Idempotency-Key of a job that was created get its id and code instead of a new job
+#[utoipa::path(
+    post,
+    path = "/create",
+    request_body = CreateRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of the request return the job it created. Ignored without an API key"),
+    ),
+    responses(
+        (status = 200, description = "Job created. it runs once activated with the code", body = CreateResponse),
+        (status = 401, description = "Invalid API key", body = CreateResponse),
+        (status = 403, description = "Plan quota exceeded", body = CreateResponse),
+        (status = 409, description = "A request with the same Idempotency-Key is in progress", body = CreateResponse),
+        (status = 422, description = "Invalid request", body = CreateResponse),
+        (status = 429, description = "Rate limited", body = CreateResponse),
+    ),
+    security((), ("api_key" = [])),
+)]
 pub async fn create_handler<T>(
     body: web::Json<CreateRequest>,
+    owner: Option<Owner>,
     state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+    limits: web::Data<Limits>,
+    policy: web::Data<UrlPolicy>,
+    idempotency: Idempotency,
 ) -> Result<HttpResponse, ApiErrors>
 where
     T: Broker,
@@ -143,29 +540,395 @@ where
     let body = body.into_inner();
     body.validate()?;
 
+    // Anonymous callers can't be told apart, their keys would replay each other's jobs and codes
+    let begin = match &owner {
+        Some(owner) => {
+            let (interval, ttl) = (body.interval.to_string(), body.ttl.to_string());
+            let fingerprint = idempotency::fingerprint(&[&body.url, &interval, &body.script, &ttl]);
+            idempotency.begin(&owner.0, fingerprint)?
+        }
+        None => None,
+    };
+    let reservation = match begin {
+        Some(Begin::Replay(created)) => {
+            return Ok(HttpResponse::Ok()
+                .header(IDEMPOTENT_REPLAYED, "true")
+                .json(CreateResponse {
+                    id: Some(created.id),
+                    code: Some(created.code),
+                    error: None,
+                    errors: None,
+                }))
+        }
+        Some(Begin::Started(reservation)) => Some(reservation),
+        None => None,
+    };
+
+    policy.check(&body.url).await?;
+
+    let plan = limits.plan(owner.as_ref());
+    plan.check_interval(body.interval)?;
+    if let (Some(owner), Some(max_jobs)) = (&owner, plan.max_jobs) {
+        check_jobs(owner, max_jobs, &state, &requests).await?;
+    }
+
     let id = uuid::Uuid::new_v4();
+    let code = activation_code();
     let msg = Messages::Create {
         id: id.to_string(),
         url: body.url,
         script: body.script,
         interval: body.interval,
+        owner: owner.map(|owner| owner.0),
+        ttl: if body.ttl == 0 { None } else { Some(body.ttl) },
+        code: code.clone(),
     };
     state.broker.lock().publish(Exchanges::Scheduler, msg).await?;
 
-    // This will be changed to either work with the Origin header, or use a port from an env variable
-    Ok(HttpResponse::Ok()
-        .header("Access-Control-Allow-Origin", "http://localhost:3000")
-        .json(CreateResponse {
-            id: Some(id.to_string()),
+    if let Some(reservation) = reservation {
+        reservation.complete(Created {
+            id: id.to_string(),
+            code: code.clone(),
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(CreateResponse {
+        id: Some(id.to_string()),
+        code: Some(code),
+        error: None,
+        errors: None,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/renew",
+    request_body = RenewRequest,
+    responses(
+        (status = 200, description = "Job renewed", body = CreateResponse),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 403, description = "Job belongs to someone else", body = CreateResponse),
+        (status = 404, description = "Job doesn't exist", body = CreateResponse),
+        (status = 409, description = "Job wasn't activated yet", body = CreateResponse),
+        (status = 422, description = "Invalid request", body = CreateResponse),
+        (status = 429, description = "Rate limited", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn renew_handler<T>(
+    body: web::Json<RenewRequest>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let body = body.into_inner();
+    body.validate()?;
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::RenewJob {
+            request_id,
+            id: body.id.clone(),
+            owner: Some(owner.0),
+        })
+        .await?;
+
+    match reply {
+        JobReply::Done => Ok(HttpResponse::Ok().json(CreateResponse {
+            id: Some(body.id),
+            code: None,
             error: None,
-        }))
+            errors: None,
+        })),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+// Concurrent requests of the same owner can each pass the check, so the quota may be exceeded by a few jobs
+async fn check_jobs<T>(
+    owner: &Owner,
+    max_jobs: usize,
+    state: &AppState<T>,
+    requests: &Requests,
+) -> Result<(), ApiErrors>
+where
+    T: Broker,
+{
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::ListJobs {
+            request_id,
+            owner: owner.0.clone(),
+            cursor: None,
+            limit: max_jobs,
+        })
+        .await?;
+
+    match reply {
+        JobReply::Jobs { jobs, .. } if jobs.len() >= max_jobs => Err(ApiErrors::QuotaExceeded(format!(
+            "Your plan allows up to {} jobs",
+            max_jobs
+        ))),
+        JobReply::Jobs { .. } => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+// Unexpected replies mean the scheduler and the api are out of sync
+fn unexpected(reply: JobReply) -> ApiErrors {
+    ApiErrors::Server(BrokerErrors::Custom(format!("Unexpected reply. {:?}", reply)))
+}
+
+fn job_response(reply: JobReply) -> Result<HttpResponse, ApiErrors> {
+    match reply {
+        JobReply::Job(job) => Ok(HttpResponse::Ok().json(job)),
+        reply => Err(unexpected(reply)),
+    }
 }
 
-pub async fn create_options() -> Result<HttpResponse, ApiErrors> {
-    Ok(HttpResponse::Ok()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "POST")
-        .header("Access-Control-Allow-Headers", "content-type")
-        .header("Access-Control-Max-Age", "86400")
-        .finish())
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 403, description = "Job belongs to someone else", body = CreateResponse),
+        (status = 404, description = "Job doesn't exist", body = CreateResponse),
+        (status = 422, description = "Invalid id", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn get_job_handler<T>(
+    id: web::Path<String>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let id = id.into_inner();
+    validate_id(&id)?;
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::GetJob {
+            request_id,
+            id,
+            owner: Some(owner.0),
+        })
+        .await?;
+
+    job_response(reply)
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "A page of the jobs of the owner", body = JobsResponse),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 422, description = "Invalid query", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn list_jobs_handler<T>(
+    query: web::Query<ListQuery>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let query = query.into_inner();
+    query.validate()?;
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::ListJobs {
+            request_id,
+            owner: owner.0,
+            cursor: query.cursor,
+            limit: query.limit,
+        })
+        .await?;
+
+    match reply {
+        JobReply::Jobs { jobs, cursor } => Ok(HttpResponse::Ok().json(JobsResponse { jobs, cursor })),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+#[utoipa::path(
+    patch,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id")),
+    request_body = UpdateRequest,
+    responses(
+        (status = 200, description = "The updated job", body = Job),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 403, description = "Job belongs to someone else or plan quota exceeded", body = CreateResponse),
+        (status = 404, description = "Job doesn't exist", body = CreateResponse),
+        (status = 409, description = "Job was changed at the same time", body = CreateResponse),
+        (status = 422, description = "Invalid request", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn update_job_handler<T>(
+    id: web::Path<String>,
+    body: web::Json<UpdateRequest>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+    limits: web::Data<Limits>,
+    policy: web::Data<UrlPolicy>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let id = id.into_inner();
+    validate_id(&id)?;
+    let body = body.into_inner();
+    body.validate()?;
+    if let Some(url) = &body.url {
+        policy.check(url).await?;
+    }
+    if let Some(interval) = body.interval {
+        limits.plan(Some(&owner)).check_interval(interval)?;
+    }
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::UpdateJob {
+            request_id,
+            id,
+            owner: Some(owner.0),
+            url: body.url,
+            script: body.script,
+            interval: body.interval,
+        })
+        .await?;
+
+    job_response(reply)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 204, description = "Job deleted"),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 403, description = "Job belongs to someone else", body = CreateResponse),
+        (status = 404, description = "Job doesn't exist", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn delete_job_handler<T>(
+    id: web::Path<String>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let id = id.into_inner();
+    validate_id(&id)?;
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::DeleteJob {
+            request_id,
+            id,
+            owner: Some(owner.0),
+        })
+        .await?;
+
+    match reply {
+        JobReply::Done => Ok(HttpResponse::NoContent().finish()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+// The scrape runs in the background. its result is sent to the subscribers as usual
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/run",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 202, description = "Scrape started. the result is sent to the subscribers"),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 403, description = "Job belongs to someone else", body = CreateResponse),
+        (status = 404, description = "Job doesn't exist", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn run_job_handler<T>(
+    id: web::Path<String>,
+    owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let id = id.into_inner();
+    validate_id(&id)?;
+
+    let reply = requests
+        .send(&state.broker, |request_id| Messages::RunJob {
+            request_id,
+            id,
+            owner: Some(owner.0),
+        })
+        .await?;
+
+    match reply {
+        JobReply::Done => Ok(HttpResponse::Accepted().finish()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+// Scripts that fail are reported with a 200, the request itself succeeded. Every request starts a
+// browser, so anonymous clients, which have no rate limit unless one is configured, can't make them
+#[utoipa::path(
+    post,
+    path = "/jobs/test",
+    request_body = TestJobRequest,
+    responses(
+        (status = 200, description = "What the script returned, or why it failed", body = TestJobResponse),
+        (status = 401, description = "Missing or invalid API key", body = CreateResponse),
+        (status = 422, description = "Invalid request", body = CreateResponse),
+        (status = 429, description = "Rate limited", body = CreateResponse),
+        (status = 504, description = "Page didn't load or the script didn't finish in time", body = CreateResponse),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn test_job_handler<T>(
+    body: web::Json<TestJobRequest>,
+    _owner: Owner,
+    state: web::Data<AppState<T>>,
+    requests: web::Data<Requests>,
+    policy: web::Data<UrlPolicy>,
+) -> Result<HttpResponse, ApiErrors>
+where
+    T: Broker,
+{
+    let body = body.into_inner();
+    body.validate()?;
+    policy.check(&body.url).await?;
+
+    let response = match requests.test_scrape(&state.broker, body.url, body.script).await? {
+        Ok(value) => TestJobResponse {
+            value: Some(value),
+            error: None,
+        },
+        Err(error) => TestJobResponse {
+            value: None,
+            error: Some(error),
+        },
+    };
+
+    Ok(HttpResponse::Ok().json(response))
 }
@@ -0,0 +1,188 @@
+use crate::{auth::Owner, ApiErrors};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    Error, HttpMessage,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+// Full buckets are dropped once there are this many, so clients that went away don't pile up
+const MAX_BUCKETS: usize = 10_000;
+
+/// What a client is allowed to do. None doesn't limit it
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    // Requests per minute, with bursts of up to a minute worth of requests
+    pub rate: Option<u32>,
+    // Jobs an owner can have at once. anonymous jobs have no owner to count them by
+    pub max_jobs: Option<usize>,
+    pub min_interval: Option<u64>,
+}
+
+impl Plan {
+    pub fn check_interval(&self, interval: u64) -> Result<(), ApiErrors> {
+        match self.min_interval {
+            Some(min_interval) if interval < min_interval => Err(ApiErrors::QuotaExceeded(format!(
+                "Interval must be at least {} seconds on your plan",
+                min_interval
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    // A minute worth of requests of the client's plan
+    capacity: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+}
+
+/// The plans of anonymous and authenticated clients, and the token buckets that limit their rate.
+/// buckets are kept in memory, so every api instance limits the clients on its own
+#[derive(Default)]
+pub struct Limits {
+    anonymous: Plan,
+    authenticated: Plan,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limits {
+    pub fn new(anonymous: Plan, authenticated: Plan) -> Self {
+        Self {
+            anonymous,
+            authenticated,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn plan(&self, owner: Option<&Owner>) -> &Plan {
+        match owner {
+            Some(_) => &self.authenticated,
+            None => &self.anonymous,
+        }
+    }
+
+    // Takes a token out of the bucket of the client, or tells how long until there is one
+    fn take(&self, client: &str, rate: u32) -> Result<(), Duration> {
+        let capacity = f64::from(rate);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.capacity
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            capacity,
+            updated: now,
+        });
+        bucket.refill(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) * 60.0 / bucket.capacity))
+    }
+}
+
+/// Limits the rate of requests per API key, or per IP for anonymous requests. has to run after
+/// Authentication, so it's wrapped before it
+pub struct RateLimit {
+    limits: Arc<Limits>,
+}
+
+impl RateLimit {
+    pub fn new(limits: Arc<Limits>) -> Self {
+        Self { limits }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limits: Arc::clone(&self.limits),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limits: Arc<Limits>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        if request.method() == Method::OPTIONS {
+            return Box::pin(self.service.call(request));
+        }
+
+        // The api is exposed directly, so the peer is the client. forwarded headers could be spoofed
+        let owner = request.extensions().get::<Owner>().cloned();
+        let client = match &owner {
+            Some(owner) => format!("owner:{}", owner.0),
+            None => match request.peer_addr() {
+                Some(address) => format!("ip:{}", address.ip()),
+                None => String::from("ip:unknown"),
+            },
+        };
+
+        if let Some(rate) = self.limits.plan(owner.as_ref()).rate {
+            if let Err(retry_after) = self.limits.take(&client, rate) {
+                let error = ApiErrors::RateLimited(retry_after);
+                return Box::pin(ready(Ok(request.error_response(error))));
+            }
+        }
+
+        Box::pin(self.service.call(request))
+    }
+}
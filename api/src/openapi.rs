@@ -0,0 +1,83 @@
+use crate::validation::{ErrorCode, FieldError};
+use crate::{
+    CreateRequest, CreateResponse, JobsResponse, RenewRequest, TestJobRequest, TestJobResponse, UpdateRequest,
+};
+use actix_web::HttpResponse;
+use broker::{Job, JobStatus};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+
+/// The OpenAPI document of the api. tests/openapi.json has to be updated along with the handlers,
+/// see the openapi_spec test
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Notifier api",
+        description = "Creates scraping jobs and manages the jobs of API key owners"
+    ),
+    paths(
+        crate::create_handler,
+        crate::renew_handler,
+        crate::list_jobs_handler,
+        crate::get_job_handler,
+        crate::update_job_handler,
+        crate::delete_job_handler,
+        crate::run_job_handler,
+        crate::test_job_handler,
+    ),
+    components(schemas(
+        CreateRequest,
+        CreateResponse,
+        RenewRequest,
+        UpdateRequest,
+        TestJobRequest,
+        TestJobResponse,
+        JobsResponse,
+        Job,
+        JobStatus,
+        FieldError,
+        ErrorCode
+    )),
+    modifiers(&ApiKeyScheme)
+)]
+pub struct ApiDoc;
+
+// API keys are sent as bearer tokens
+struct ApiKeyScheme;
+
+impl Modify for ApiKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme("api_key", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        }
+    }
+}
+
+pub async fn openapi_handler() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+// Swagger UI from a CDN, so the api doesn't have to ship it
+const DOCS_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Notifier api</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="docs"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+      SwaggerUIBundle({ url: "/openapi.json", dom_id: "#docs" });
+    </script>
+  </body>
+</html>
+"##;
+
+pub async fn docs_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DOCS_PAGE)
+}
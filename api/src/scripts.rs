@@ -0,0 +1,77 @@
+use crate::validation::ErrorCode;
+use boa_ast::{
+    expression::ImportCall,
+    visitor::{VisitWith, Visitor},
+};
+use boa_interner::{Interner, Sym};
+use boa_parser::{Parser, Source};
+use std::ops::ControlFlow;
+
+pub(crate) const MAX_SCRIPT_LENGTH: usize = 10_000; // Bytes
+
+// Scripts run in the page of the job, so they could send its content elsewhere or act as the
+// visitor. matched against identifiers, property names and strings, so obj["fetch"] is caught too.
+// it's a best effort, the scraper blocks the network after the page loads as well
+const DENIED_APIS: &[&str] = &[
+    "fetch",
+    "XMLHttpRequest",
+    "WebSocket",
+    "EventSource",
+    "RTCPeerConnection",
+    "sendBeacon",
+    "Worker",
+    "SharedWorker",
+    "serviceWorker",
+    "importScripts",
+    "eval",
+    "Function",
+    "cookie",
+    "localStorage",
+    "sessionStorage",
+    "indexedDB",
+];
+
+struct DeniedApis<'a> {
+    interner: &'a Interner,
+}
+
+impl<'ast> Visitor<'ast> for DeniedApis<'_> {
+    type BreakTy = ();
+
+    fn visit_sym(&mut self, sym: &'ast Sym) -> ControlFlow<Self::BreakTy> {
+        let name = self.interner.resolve_expect(*sym).to_string();
+        if DENIED_APIS.contains(&name.as_str()) {
+            return ControlFlow::Break(());
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    // import() loads code from anywhere
+    fn visit_import_call(&mut self, _: &'ast ImportCall) -> ControlFlow<Self::BreakTy> {
+        ControlFlow::Break(())
+    }
+}
+
+// Checks the script the way the scraper runs it, as the body of a function
+pub fn check(script: &str) -> Result<(), ErrorCode> {
+    if script.is_empty() {
+        return Err(ErrorCode::EmptyScript);
+    }
+
+    if script.len() > MAX_SCRIPT_LENGTH {
+        return Err(ErrorCode::ScriptTooLong);
+    }
+
+    let wrapped = format!("(() => {{\n{}\n}})();", script);
+    let mut interner = Interner::default();
+    let parsed = Parser::new(Source::from_bytes(&wrapped)).parse_script(&mut interner);
+    let parsed = parsed.map_err(|_| ErrorCode::InvalidScriptSyntax)?;
+
+    let mut visitor = DeniedApis { interner: &interner };
+    if parsed.visit_with(&mut visitor).is_break() {
+        return Err(ErrorCode::DeniedScriptApi);
+    }
+
+    Ok(())
+}
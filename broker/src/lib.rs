@@ -50,6 +50,7 @@ pub enum Exchanges {
     Scheduler,
     Scraper,
     Bot,
+    Api,
 }
 
 impl fmt::Display for Exchanges {
@@ -58,10 +59,64 @@ impl fmt::Display for Exchanges {
             Self::Scheduler => write!(f, "scheduler"),
             Self::Scraper => write!(f, "scraper"),
             Self::Bot => write!(f, "bot"),
+            Self::Api => write!(f, "api"),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    // Created but not activated yet
+    Pending,
+    Active,
+    // Activated but nobody is subscribed, so it doesn't run
+    Paused,
+}
+
+/// A job as the scheduler reports it to the api
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Job {
+    pub id: String,
+    pub url: String,
+    pub script: String,
+    pub interval: u64,
+    pub owner: Option<String>,
+    pub status: JobStatus,
+    pub subscribers: usize,
+    pub ttl: Option<u64>,
+    // Unix timestamp in seconds
+    pub expires_at: Option<u64>,
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<Object>))]
+    pub last_value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum JobReply {
+    Job(Job),
+    // A page of jobs ordered by id. cursor is passed to the next request to get the following page
+    Jobs { jobs: Vec<Job>, cursor: Option<String> },
+    Done,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum JobError {
+    NotFound,
+    Forbidden,
+    // The job kept changing while the scheduler tried to update it
+    Conflict,
+    // The job wasn't activated with its code yet
+    NotActivated,
+    Internal(String),
+}
+
+pub type JobResult = Result<JobReply, JobError>;
+
+// What the script returned, or why the page couldn't be scraped or the script failed
+pub type ScrapeResult = Result<serde_json::Value, String>;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Messages {
     // api -> scheduler
@@ -70,39 +125,166 @@ pub enum Messages {
         url: String,
         script: String,
         interval: u64,
+        owner: Option<String>,
+        // Seconds until the record expires once it's activated. None means that it never expires
+        ttl: Option<u64>,
+        // One time code that activates the record
+        code: String,
+    },
+    // bot -> scheduler
+    Renew {
+        id: String,
+        chat_id: String,
     },
     // bot -> scheduler
     Delete {
         id: String,
+        chat_id: String,
+    },
+    // bot -> scheduler. allows user_id to subscribe to the record without being able to modify it
+    Share {
+        id: String,
+        chat_id: String,
+        user_id: String,
     },
     // bot -> scheduler
+    Transfer {
+        id: String,
+        chat_id: String,
+        user_id: String,
+    },
+    // bot -> scheduler. claims the record that was created with this code
     Activate {
+        code: String,
+        chat_id: String,
+    },
+    // scheduler -> bot
+    Activated {
+        id: String,
+        chat_id: String,
+    },
+    // scheduler -> bot. the code doesn't exist, was already used or has expired
+    ActivationFailed {
+        code: String,
+        chat_id: String,
+    },
+    // bot -> scheduler. subscribes to an already activated record, by its owner or a user it was shared with
+    Subscribe {
+        id: String,
+        chat_id: String,
+    },
+    // bot -> scheduler
+    Unsubscribe {
         id: String,
         chat_id: String,
     },
     // scheduler -> scraper
     Scrape {
         id: String,
-        chat_id: Option<String>,
         url: String,
         script: String,
     },
-    // scraper -> bot
+    // scraper -> scheduler
+    ScrapeResponse {
+        id: String,
+        // Whatever the script returned. undefined is sent as null
+        value: serde_json::Value,
+    },
+    // scheduler -> bot
     Notify {
         id: String,
         chat_id: String,
         url: String,
+        // The value returned by the previous run, if there was one
+        previous: Option<serde_json::Value>,
+        current: serde_json::Value,
     },
     // bot -> scheduler
     List {
         chat_id: String,
     },
     // scheduler -> bot
+    Expiring {
+        id: String,
+        chat_id: String,
+        url: String,
+        // Unix timestamp in seconds
+        expires_at: u64,
+    },
+    // scheduler -> bot. the record expired and was removed
+    Expired {
+        id: String,
+        chat_id: String,
+        url: String,
+    },
+    // scheduler -> bot. chat_id is not allowed to do what it asked for with the record
+    Forbidden {
+        id: String,
+        chat_id: String,
+    },
+    // scheduler -> bot
     ListResponse {
         // (url, id)
         records: Vec<(String, String)>,
         chat_id: String,
     },
+    // api -> scheduler. every job request has a request_id, which the scheduler sends back in the
+    // JobResponse. owner restricts the request to the jobs of that owner
+    GetJob {
+        request_id: String,
+        id: String,
+        owner: Option<String>,
+    },
+    // api -> scheduler
+    ListJobs {
+        request_id: String,
+        owner: String,
+        cursor: Option<String>,
+        limit: usize,
+    },
+    // api -> scheduler. fields that are None are left as they are
+    UpdateJob {
+        request_id: String,
+        id: String,
+        owner: Option<String>,
+        url: Option<String>,
+        script: Option<String>,
+        interval: Option<u64>,
+    },
+    // api -> scheduler
+    DeleteJob {
+        request_id: String,
+        id: String,
+        owner: Option<String>,
+    },
+    // api -> scheduler. scrapes the job right away instead of waiting for its interval
+    RunJob {
+        request_id: String,
+        id: String,
+        owner: Option<String>,
+    },
+    // api -> scheduler. restarts the countdown of the job's ttl
+    RenewJob {
+        request_id: String,
+        id: String,
+        owner: Option<String>,
+    },
+    // scheduler -> api
+    JobResponse {
+        request_id: String,
+        result: JobResult,
+    },
+    // api -> scraper. runs the script once without creating a job, to test it
+    TestScrape {
+        request_id: String,
+        url: String,
+        script: String,
+    },
+    // scraper -> api
+    TestScrapeResponse {
+        request_id: String,
+        result: ScrapeResult,
+    },
 }
 
 pub struct Consumer {
@@ -1,15 +1,70 @@
-use broker::{Broker, Exchanges, Rabbit};
+use broker::{Broker, Exchanges, Messages, Rabbit};
 use log::{error, info};
-use scheduler::{redis_store::RedisStore, Scheduler};
-use std::env;
-use tokio_stream::StreamExt;
+use scheduler::{
+    cached_store::CachedStore,
+    fs_store::FileStore,
+    redis_store::{RedisStore, DEFAULT_NAMESPACE},
+    sql_store::SqlStore,
+    store::Store,
+    transfer, Config, Scheduler, SchedulerErrors,
+};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader},
+    sync::Arc,
+    time::Duration,
+};
+use tokio_stream::{Stream, StreamExt};
+
+// Number of records kept in memory in front of the store
+const DEFAULT_CACHE_CAPACITY: usize = 1_000;
+// How often the corrections made by reconciliation are logged
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     pretty_env_logger::init();
 
+    // Maintenance commands work on the store directly, without the broker
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(error) = command(&args).await {
+            error!("scheduler.command. {}", error);
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     let rabbit_host = env::var("RABBIT_HOST").expect("Can't find RABBIT_HOST env variable");
-    let redis_host = env::var("REDIS_HOST").expect("Can't find REDIS_HOST env variable");
+
+    let mut config = Config::default();
+    if let Ok(seconds) = env::var("EXPIRY_WARNING_SECONDS") {
+        let seconds = seconds.parse::<u64>().expect("EXPIRY_WARNING_SECONDS must be a number");
+        config.expiry_warning = Duration::from_secs(seconds);
+    }
+    if let Ok(seconds) = env::var("PENDING_TTL_SECONDS") {
+        let seconds = seconds.parse::<u64>().expect("PENDING_TTL_SECONDS must be a number");
+        config.pending_ttl = Duration::from_secs(seconds);
+    }
+    if let Ok(seconds) = env::var("RECONCILE_INTERVAL_SECONDS") {
+        let seconds = seconds
+            .parse::<u64>()
+            .expect("RECONCILE_INTERVAL_SECONDS must be a number");
+        config.reconcile_interval = Duration::from_secs(seconds);
+    }
+    let stats_interval = match env::var("STATS_INTERVAL_SECONDS") {
+        Ok(seconds) => {
+            let seconds = seconds.parse::<u64>().expect("STATS_INTERVAL_SECONDS must be a number");
+            Duration::from_secs(seconds)
+        }
+        Err(_) => DEFAULT_STATS_INTERVAL,
+    };
+    let cache_capacity = match env::var("CACHE_CAPACITY") {
+        Ok(capacity) => capacity.parse::<usize>().expect("CACHE_CAPACITY must be a number"),
+        Err(_) => DEFAULT_CACHE_CAPACITY,
+    };
 
     let broker = match Rabbit::new(&rabbit_host).await {
         Ok(broker) => broker,
@@ -26,30 +81,163 @@ async fn main() -> std::io::Result<()> {
             std::process::exit(1);
         }
     };
-    let mut consumer = consumer.into_inner();
+    let consumer = consumer.into_inner();
 
-    let redis_store = match RedisStore::new(&redis_host).await {
-        Ok(redis) => redis,
-        Err(error) => {
-            error!("scheduler.RedisStore.new. {}", error);
-            std::process::exit(1);
-        }
-    };
+    // Small deployments can keep the records in a local file or database instead of Redis
+    if let Ok(path) = env::var("SQLITE_PATH") {
+        let sql_store = match SqlStore::new(&path).await {
+            Ok(sql_store) => sql_store,
+            Err(error) => {
+                error!("scheduler.SqlStore.new. {}", error);
+                std::process::exit(1);
+            }
+        };
+
+        run(
+            broker,
+            consumer,
+            CachedStore::new(sql_store, cache_capacity),
+            config,
+            stats_interval,
+        )
+        .await;
+    } else if let Ok(path) = env::var("FILE_STORE_PATH") {
+        let file_store = match FileStore::new(&path).await {
+            Ok(file_store) => file_store,
+            Err(error) => {
+                error!("scheduler.FileStore.new. {}", error);
+                std::process::exit(1);
+            }
+        };
+
+        run(
+            broker,
+            consumer,
+            CachedStore::new(file_store, cache_capacity),
+            config,
+            stats_interval,
+        )
+        .await;
+    } else {
+        let redis_host = env::var("REDIS_HOST").expect("Can't find REDIS_HOST env variable");
+        // Deployments that share a Redis database need different namespaces
+        let namespace = env::var("REDIS_NAMESPACE").unwrap_or_else(|_| String::from(DEFAULT_NAMESPACE));
+        let redis_store = match RedisStore::new(&redis_host, &namespace).await {
+            Ok(redis) => redis,
+            Err(error) => {
+                error!("scheduler.RedisStore.new. {}", error);
+                std::process::exit(1);
+            }
+        };
+
+        run(
+            broker,
+            consumer,
+            CachedStore::new(redis_store, cache_capacity),
+            config,
+            stats_interval,
+        )
+        .await;
+    }
 
-    let scheduler = match Scheduler::new(broker, redis_store).await {
-        Ok(scheduler) => scheduler,
+    Ok(())
+}
+
+async fn run<T, U, C>(broker: T, mut consumer: C, store: U, config: Config, stats_interval: Duration)
+where
+    T: Broker + Sync + Send + 'static,
+    U: Store + Sync + Send + 'static,
+    C: Stream<Item = Messages> + Unpin,
+{
+    let scheduler = match Scheduler::new(broker, store, config).await {
+        Ok(scheduler) => Arc::new(scheduler),
         Err(error) => {
             error!("scheduler.Scheduler.new. {}", error);
             std::process::exit(1);
         }
     };
 
+    launch_stats(Arc::clone(&scheduler), stats_interval);
+
     info!("Listening for messages in scheduler");
     while let Some(value) = consumer.next().await {
         if let Err(error) = scheduler.receive(value).await {
             error!("scheduler.receive. {}", error);
         }
     }
+}
+
+// A schedule that keeps being corrected means something changes the store behind the scheduler's back
+fn launch_stats<T, U>(scheduler: Arc<Scheduler<T, U>>, period: Duration)
+where
+    T: Broker + Sync + Send + 'static,
+    U: Store + Sync + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        loop {
+            interval.tick().await;
+
+            let stats = scheduler.reconcile_stats();
+            info!(
+                "scheduler.reconcile_stats. passes: {}. added: {}. removed: {}. updated: {}",
+                stats.passes, stats.added, stats.removed, stats.updated
+            );
+        }
+    });
+}
+
+// The store the scheduler runs with, in the format of transfer::open
+fn store_spec() -> String {
+    if let Ok(path) = env::var("SQLITE_PATH") {
+        format!("sqlite:{}", path)
+    } else if let Ok(path) = env::var("FILE_STORE_PATH") {
+        format!("file:{}", path)
+    } else {
+        let redis_host = env::var("REDIS_HOST").expect("Can't find REDIS_HOST env variable");
+        match env::var("REDIS_NAMESPACE") {
+            Ok(namespace) => format!("{}#{}", redis_host, namespace),
+            Err(_) => redis_host,
+        }
+    }
+}
+
+// scheduler export [file]
+// scheduler import [file]
+// scheduler migrate <from> <to>
+//
+// export and import use the configured store and stdout or stdin when no file is given. the stores
+// of migrate are either a Redis url with an optional #<namespace>, file:<path> or sqlite:<path>
+async fn command(args: &[String]) -> Result<(), SchedulerErrors> {
+    match (args[0].as_str(), args.get(1), args.get(2)) {
+        ("export", file, None) => {
+            let store = transfer::open(&store_spec()).await?;
+            let count = match file {
+                Some(file) => transfer::export(&*store, File::create(file)?).await?,
+                None => transfer::export(&*store, io::stdout()).await?,
+            };
+            info!("Exported {} records", count);
+        }
+        ("import", file, None) => {
+            let store = transfer::open(&store_spec()).await?;
+            let count = match file {
+                Some(file) => transfer::import(&*store, BufReader::new(File::open(file)?)).await?,
+                None => transfer::import(&*store, io::stdin().lock()).await?,
+            };
+            info!("Imported {} records", count);
+        }
+        ("migrate", Some(from), Some(to)) => {
+            let source = transfer::open(from).await?;
+            let destination = transfer::open(to).await?;
+            let count = transfer::copy(&*source, &*destination).await?;
+            info!("Migrated {} records", count);
+        }
+        _ => {
+            eprintln!("Usage: scheduler [export [file] | import [file] | migrate <from> <to>]");
+            std::process::exit(2);
+        }
+    }
 
     Ok(())
 }
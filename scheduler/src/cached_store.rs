@@ -0,0 +1,442 @@
+use crate::store::{current_time, Code, Event, Page, Query, Record, Store};
+use crate::SchedulerErrors;
+use async_trait::async_trait;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+};
+use tokio::sync::broadcast::{self, RecvError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+// Records in memory, and the keys that are being read from the wrapped store
+struct Cache {
+    records: LruCache<String, Record>,
+    // Generation of every key with a read in progress. invalidate bumps it, so a read that started
+    // before doesn't put back the record it got
+    reads: HashMap<String, Read>,
+    // Number of values set through this store whose events haven't arrived yet. the values are set
+    // on the cached records already, so their events don't drop them
+    set_values: HashMap<String, usize>,
+}
+
+struct Read {
+    generation: u64,
+    readers: usize,
+}
+
+impl Cache {
+    fn invalidate(&mut self, id: &str) {
+        self.records.pop(&id.to_string());
+        if let Some(read) = self.reads.get_mut(id) {
+            read.generation += 1;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+        for read in self.reads.values_mut() {
+            read.generation += 1;
+        }
+        // Their events might have been among the missed ones
+        self.set_values.clear();
+    }
+
+    // Whether the event is the one of a value set through this store, which it's then no longer waited for
+    fn take_set_value(&mut self, event: &Event) -> bool {
+        let id = match event {
+            Event::Updated { id } => id,
+            _ => return false,
+        };
+
+        match self.set_values.get_mut(id) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                self.set_values.remove(id);
+            }
+            None => return false,
+        }
+
+        true
+    }
+}
+
+// A read of a key from the wrapped store. the key stops being tracked once its last read is dropped,
+// whether it finished or not
+struct Reading<'a> {
+    cache: &'a Mutex<Cache>,
+    key: String,
+    generation: u64,
+}
+
+impl<'a> Reading<'a> {
+    fn start(cache: &'a Mutex<Cache>, key: String) -> Self {
+        let mut locked = cache.lock();
+        let read = locked.reads.entry(key.clone()).or_insert(Read {
+            generation: 0,
+            readers: 0,
+        });
+        read.readers += 1;
+        let generation = read.generation;
+
+        Reading { cache, key, generation }
+    }
+
+    // Caches the record unless the key was invalidated since the read started
+    fn finish(self, record: &Record) {
+        let mut cache = self.cache.lock();
+        let current = cache.reads.get(&self.key).map(|read| read.generation);
+        if current == Some(self.generation) {
+            cache.records.put(self.key.clone(), record.clone());
+        }
+    }
+
+    // Sets the value on the cached record, which the wrapped store has just done as well. the record
+    // is dropped instead if the key was invalidated, or another version was cached, since the write
+    // started. reads still in progress may have got the record from before the write, so they don't
+    // put it back either way
+    fn set_value(self, version: Option<u64>, value: &serde_json::Value) {
+        let mut cache = self.cache.lock();
+        let current = cache.reads.get(&self.key).map(|read| read.generation);
+        let record = match cache.records.pop(&self.key) {
+            Some(record) if current == Some(self.generation) && Some(record.version) == version => Some(record),
+            _ => None,
+        };
+        cache.invalidate(&self.key);
+
+        if let Some(mut record) = record {
+            record.last_value = Some(value.clone());
+            record.version += 1;
+            cache.records.put(self.key.clone(), record);
+        }
+    }
+}
+
+impl Drop for Reading<'_> {
+    fn drop(&mut self) {
+        let mut cache = self.cache.lock();
+        if let Some(read) = cache.reads.get_mut(&self.key) {
+            read.readers -= 1;
+            if read.readers == 0 {
+                cache.reads.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Keeps the most recently read records in memory in front of another store. Every write goes
+/// to the wrapped store and drops the record from the cache, so the next read gets the new version,
+/// except for values, which are set on the cached record as well. The events of the wrapped store
+/// drop records too, and they include the writes of other schedulers sharing it
+pub struct CachedStore<S> {
+    store: S,
+    cache: Arc<Mutex<Cache>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<S> CachedStore<S>
+where
+    S: Store + Sync + Send,
+{
+    pub fn new(store: S, capacity: usize) -> Self {
+        let cache = Arc::new(Mutex::new(Cache {
+            records: LruCache::new(capacity),
+            reads: HashMap::new(),
+            set_values: HashMap::new(),
+        }));
+        Self::launch_invalidation(store.events(), Arc::downgrade(&cache));
+
+        Self {
+            store,
+            cache,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Drops the records the wrapped store reports as changed. The task stops together with the store
+    fn launch_invalidation(mut events: broadcast::Receiver<Event>, cache: Weak<Mutex<Cache>>) {
+        tokio::spawn(async move {
+            loop {
+                let event = events.recv().await;
+                let cache = match cache.upgrade() {
+                    Some(cache) => cache,
+                    None => return,
+                };
+
+                match event {
+                    Ok(event) => {
+                        let mut cache = cache.lock();
+                        if !cache.take_set_value(&event) {
+                            cache.invalidate(event.id());
+                        }
+                    }
+                    // Any of the records might have changed in the events that were missed
+                    Err(RecvError::Lagged(_)) => cache.lock().clear(),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.lock().records.len(),
+        }
+    }
+
+    fn invalidate(&self, id: &str) {
+        self.cache.lock().invalidate(id);
+    }
+
+    // Drops the record once a write to the wrapped store is done, even if it failed since it might
+    // have been applied partially
+    fn written<T>(&self, id: &str, result: Result<T, SchedulerErrors>) -> Result<T, SchedulerErrors> {
+        self.invalidate(id);
+
+        result
+    }
+}
+
+#[async_trait]
+impl<S> Store for CachedStore<S>
+where
+    S: Store + Sync + Send,
+{
+    async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
+        self.store.load().await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
+        let key = id.to_string();
+        let cached = {
+            let records = &mut self.cache.lock().records;
+            match records.get(&key).cloned() {
+                // Records expire in the wrapped store by themselves
+                Some(record) if matches!(record.expires_at, Some(expires_at) if expires_at <= current_time()) => {
+                    records.pop(&key);
+                    None
+                }
+                cached => cached,
+            }
+        };
+
+        if let Some(record) = cached {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(record));
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let reading = Reading::start(&self.cache, key);
+        let record = self.store.get(id).await?;
+        if let Some(record) = &record {
+            reading.finish(record);
+        }
+
+        Ok(record)
+    }
+
+    async fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
+        let id = record.id.clone();
+
+        self.written(&id, self.store.add(record).await)
+    }
+
+    async fn subscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.subscribe(id, chat_id).await)
+    }
+
+    async fn unsubscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.unsubscribe(id, chat_id).await)
+    }
+
+    async fn share(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.share(id, chat_id).await)
+    }
+
+    async fn set_owner(&self, id: &str, owner: &str) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.set_owner(id, owner).await)
+    }
+
+    async fn renew(&self, id: &str, expires_at: Option<u64>) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.renew(id, expires_at).await)
+    }
+
+    async fn add_code(&self, code: &str, id: &str, expires_at: u64) -> Result<(), SchedulerErrors> {
+        self.store.add_code(code, id, expires_at).await
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<String>, SchedulerErrors> {
+        self.store.take_code(code).await
+    }
+
+    async fn codes(&self) -> Result<Vec<Code>, SchedulerErrors> {
+        self.store.codes().await
+    }
+
+    // The record is read again before every run of its script, which sets the value after it. the
+    // cached record is updated instead of dropped, so those reads keep hitting the cache
+    async fn set_value(&self, id: &str, value: &serde_json::Value) -> Result<(), SchedulerErrors> {
+        let key = id.to_string();
+        let reading = Reading::start(&self.cache, key.clone());
+        let version = {
+            let mut cache = self.cache.lock();
+            *cache.set_values.entry(key.clone()).or_default() += 1;
+            cache.records.peek(&key).map(|record| record.version)
+        };
+
+        let result = self.store.set_value(id, value).await;
+        match &result {
+            Ok(()) => reading.set_value(version, value),
+            Err(_) => {
+                let mut cache = self.cache.lock();
+                cache.take_set_value(&Event::Updated { id: key });
+                cache.invalidate(id);
+            }
+        }
+
+        result
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), SchedulerErrors> {
+        self.written(id, self.store.delete(id).await)
+    }
+
+    async fn compare_and_set(&self, record: Record) -> Result<bool, SchedulerErrors> {
+        let id = record.id.clone();
+
+        self.written(&id, self.store.compare_and_set(record).await)
+    }
+
+    fn events(&self) -> broadcast::Receiver<Event> {
+        self.store.events()
+    }
+
+    // Queries go to the wrapped store, so its indexes are used
+    async fn query(&self, query: &Query, cursor: Option<&str>, limit: usize) -> Result<Page, SchedulerErrors> {
+        self.store.query(query, cursor, limit).await
+    }
+
+    async fn count(&self, query: &Query) -> Result<usize, SchedulerErrors> {
+        self.store.count(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_store::FileStore;
+    use crate::store::tests::record;
+    use serde_json::json;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn cached_store() -> (CachedStore<FileStore>, TempDir) {
+        let directory = TempDir::new().unwrap();
+        let store = FileStore::new(directory.path().join("records.jsonl")).await.unwrap();
+
+        (CachedStore::new(store, 10), directory)
+    }
+
+    // Waits until the events sent so far were handled. they're handled in order, so once the event of
+    // a record added behind the cache's back drops it, the earlier ones were handled too
+    async fn settle(store: &CachedStore<FileStore>) {
+        let marker = record("marker");
+        store.cache.lock().records.put(marker.id.clone(), marker.clone());
+        store.store.add(marker).await.unwrap();
+
+        for _ in 0..100 {
+            if !store.cache.lock().records.contains(&String::from("marker")) {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("events were not handled");
+    }
+
+    #[tokio::test]
+    async fn writes_invalidate() {
+        let (store, _directory) = cached_store().await;
+        store.add(record("a")).await.unwrap();
+        // The event of the write drops the record once more when it arrives
+        settle(&store).await;
+
+        store.get("a").await.unwrap();
+        store.get("a").await.unwrap();
+        assert_eq!(store.stats().hits, 1);
+        assert_eq!(store.stats().misses, 1);
+
+        store.subscribe("a", "chat").await.unwrap();
+        let record = store.get("a").await.unwrap().unwrap();
+        assert!(record.subscribers.contains("chat"));
+        assert_eq!(store.stats().misses, 2);
+    }
+
+    #[tokio::test]
+    async fn set_value_updates_cached() {
+        let (store, _directory) = cached_store().await;
+        store.add(record("a")).await.unwrap();
+        settle(&store).await;
+        store.get("a").await.unwrap();
+
+        store.set_value("a", &json!(1)).await.unwrap();
+        settle(&store).await;
+
+        let record = store.get("a").await.unwrap().unwrap();
+        assert_eq!(record.last_value, Some(json!(1)));
+        assert_eq!(record.version, store.store.get("a").await.unwrap().unwrap().version);
+        assert_eq!(store.stats().hits, 1);
+        assert!(store.cache.lock().set_values.is_empty());
+
+        // Another scheduler sharing the wrapped store
+        store.store.set_value("a", &json!(2)).await.unwrap();
+        settle(&store).await;
+        assert_eq!(store.get("a").await.unwrap().unwrap().last_value, Some(json!(2)));
+    }
+
+    #[tokio::test]
+    async fn events_invalidate() {
+        let (store, _directory) = cached_store().await;
+        store.add(record("a")).await.unwrap();
+        store.get("a").await.unwrap();
+
+        // Another scheduler sharing the wrapped store
+        store.store.set_value("a", &json!(1)).await.unwrap();
+
+        for _ in 0..100 {
+            if store.get("a").await.unwrap().unwrap().last_value == Some(json!(1)) {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("record was not invalidated");
+    }
+
+    #[tokio::test]
+    async fn invalidated_reads_not_cached() {
+        let (store, _directory) = cached_store().await;
+
+        let reading = Reading::start(&store.cache, String::from("a"));
+        store.invalidate("a");
+        reading.finish(&record("a"));
+        assert_eq!(store.stats().entries, 0);
+        assert!(store.cache.lock().reads.is_empty());
+
+        let reading = Reading::start(&store.cache, String::from("a"));
+        reading.finish(&record("a"));
+        assert_eq!(store.stats().entries, 1);
+    }
+}
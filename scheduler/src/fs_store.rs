@@ -1,81 +1,615 @@
-use crate::store::{Record, Store};
+use crate::store::{current_time, Code, Event, Events, Record, Store, EXPIRY_CHECK_INTERVAL};
 use crate::SchedulerErrors;
-use csv::{Reader, WriterBuilder};
+use async_trait::async_trait;
+use log::error;
 use parking_lot::Mutex;
-use std::io::{Seek, SeekFrom};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::{
     collections::HashMap,
-    fs::{File, OpenOptions},
-    path::Path,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
 };
+use tokio::sync::broadcast;
 
+// The log is rewritten once it has this many entries more than there are live records and codes
+const COMPACTION_THRESHOLD: usize = 1_000;
+
+/// A single line of the log. The state of the store is the result of replaying all of them
+#[derive(Debug, Serialize, Deserialize)]
+enum Entry {
+    Put(Box<Record>),
+    Delete { id: String },
+    AddCode { code: String, id: String, expires_at: u64 },
+    TakeCode { code: String },
+}
+
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    matches!(expires_at, Some(expires_at) if expires_at <= now)
+}
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    records: HashMap<String, Record>,
+    // Activation code to the id it activates and when it expires
+    codes: HashMap<String, (String, u64)>,
+    // Number of entries in the log, used to decide when to compact it
+    entries: usize,
+    // Length of the log up to the last complete entry
+    len: u64,
+    events: Events,
+}
+
+impl Inner {
+    fn open(path: PathBuf, events: Events) -> Result<Self, SchedulerErrors> {
+        let mut inner = Inner {
+            file: OpenOptions::new().read(true).append(true).create(true).open(&path)?,
+            path,
+            records: HashMap::new(),
+            codes: HashMap::new(),
+            entries: 0,
+            len: 0,
+            events,
+        };
+
+        // Split into bytes, a torn write can cut a character in half
+        let reader = BufReader::new(File::open(&inner.path)?);
+        let mut lines = reader.split(b'\n').enumerate().peekable();
+        while let Some((number, line)) = lines.next() {
+            let line = line?;
+
+            match serde_json::from_slice::<Entry>(&line) {
+                Ok(entry) => inner.apply(entry),
+                // A crash in the middle of a write leaves a partial last line behind
+                Err(error) if lines.peek().is_none() => {
+                    error!("scheduler.fs_store.open. skipping the last line. {}", error);
+                }
+                // Dropping it would lose the entries after it once the log is compacted
+                Err(error) => {
+                    error!("scheduler.fs_store.open. line {} is corrupt. {}", number + 1, error);
+                    return Err(error.into());
+                }
+            }
+        }
+
+        // Start with a clean log, without expired records or a partial last line
+        inner.compact()?;
+
+        Ok(inner)
+    }
+
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Put(record) => {
+                self.records.insert(record.id.clone(), *record);
+            }
+            Entry::Delete { id } => {
+                self.records.remove(&id);
+            }
+            Entry::AddCode { code, id, expires_at } => {
+                self.codes.insert(code, (id, expires_at));
+            }
+            Entry::TakeCode { code } => {
+                self.codes.remove(&code);
+            }
+        }
+
+        self.entries += 1;
+    }
+
+    // Logs and applies the entry, and reports the change of the record if there is one
+    fn write(&mut self, entry: Entry) -> Result<(), SchedulerErrors> {
+        let event = match &entry {
+            Entry::Put(record) => Some(Event::changed(self.get(&record.id), record)),
+            Entry::Delete { id } if self.records.contains_key(id) => Some(Event::Deleted { id: id.clone() }),
+            _ => None,
+        };
+
+        self.append(entry)?;
+        if let Some(event) = event {
+            self.events.send(event);
+        }
+
+        Ok(())
+    }
+
+    // Appends the entry to the log before applying it
+    fn append(&mut self, entry: Entry) -> Result<(), SchedulerErrors> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let written = self.file.write_all(&line).and_then(|_| self.file.sync_data());
+        if let Err(error) = written {
+            // A partial entry in the middle of the log would make it unreadable
+            if let Err(error) = self.file.set_len(self.len) {
+                error!("scheduler.fs_store.append.truncate. {}", error);
+            }
+            return Err(error.into());
+        }
+        self.len += line.len() as u64;
+        self.apply(entry);
+
+        // The entry is in the log already, so the write succeeded even if compacting fails. the
+        // log is compacted again after the next one
+        if self.entries > self.records.len() + self.codes.len() + COMPACTION_THRESHOLD {
+            if let Err(error) = self.compact() {
+                error!("scheduler.fs_store.append.compact. {}", error);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the live records and codes into a new file and replaces the log with it. The rename
+    // is atomic, so after a crash there's either the old log or the new one
+    fn compact(&mut self) -> Result<(), SchedulerErrors> {
+        let now = current_time();
+        let expired: Vec<String> = self
+            .records
+            .values()
+            .filter(|record| is_expired(record.expires_at, now))
+            .map(|record| record.id.clone())
+            .collect();
+        for id in expired {
+            if let Some(record) = self.records.remove(&id) {
+                self.events.send(Event::Expired(Box::new(record)));
+            }
+        }
+        self.codes.retain(|_, (_, expires_at)| *expires_at > now);
+
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            for record in self.records.values() {
+                serde_json::to_writer(&mut writer, &Entry::Put(Box::new(record.clone())))?;
+                writer.write_all(b"\n")?;
+            }
+            for (code, (id, expires_at)) in self.codes.iter() {
+                let entry = Entry::AddCode {
+                    code: code.clone(),
+                    id: id.clone(),
+                    expires_at: *expires_at,
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writer.write_all(b"\n")?;
+            }
+
+            let file = writer.into_inner().map_err(|error| error.into_error())?;
+            file.sync_all()?;
+        }
+
+        // Opened before the rename, so a failure leaves the store with the old log to append to
+        let file = OpenOptions::new().read(true).append(true).open(&temp_path)?;
+        let len = file.metadata()?.len();
+        fs::rename(&temp_path, &self.path)?;
+        self.file = file;
+        self.entries = self.records.len() + self.codes.len();
+        self.len = len;
+
+        // The rename itself is durable only once the directory is synced
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    // Deletes the records that expired since the last check
+    fn expire(&mut self) -> Result<(), SchedulerErrors> {
+        let now = current_time();
+        let expired: Vec<Record> = self
+            .records
+            .values()
+            .filter(|record| is_expired(record.expires_at, now))
+            .cloned()
+            .collect();
+
+        for record in expired {
+            // A compaction on the way removes the rest of them by itself
+            if !self.records.contains_key(&record.id) {
+                continue;
+            }
+
+            self.append(Entry::Delete { id: record.id.clone() })?;
+            self.events.send(Event::Expired(Box::new(record)));
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Option<&Record> {
+        self.records
+            .get(id)
+            .filter(|record| !is_expired(record.expires_at, current_time()))
+    }
+
+    // Applies a change to a record and logs its new state
+    fn update<F>(&mut self, id: &str, change: F) -> Result<(), SchedulerErrors>
+    where
+        F: FnOnce(&mut Record),
+    {
+        let mut record = match self.get(id) {
+            Some(record) => record.clone(),
+            None => return Err(SchedulerErrors::NotFound(id.to_string())),
+        };
+        change(&mut record);
+        record.version += 1;
+
+        self.write(Entry::Put(Box::new(record)))
+    }
+}
+
+/// Embedded store for small deployments that run without Redis. Records are kept in memory and
+/// every change is appended to a JSON lines log, which is compacted once it grows too large
 pub struct FileStore {
-    file: Arc<Mutex<File>>,
+    inner: Arc<Mutex<Inner>>,
+    events: Events,
 }
 
 impl FileStore {
-    pub fn new<P>(path: P) -> Result<Self, SchedulerErrors>
+    pub async fn new<P>(path: P) -> Result<Self, SchedulerErrors>
     where
         P: AsRef<Path>,
     {
-        let file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
-        let file = Arc::new(Mutex::new(file));
+        let path = path.as_ref().to_path_buf();
+        let events = Events::default();
+        let inner_events = events.clone();
+        let inner = tokio::task::spawn_blocking(move || Inner::open(path, inner_events)).await??;
+
+        let store = Self {
+            inner: Arc::new(Mutex::new(inner)),
+            events,
+        };
+        store.launch_expiry();
+
+        Ok(store)
+    }
+
+    // Deletes expired records as soon as they expire, so they're reported right away. The task
+    // stops once the store is dropped
+    fn launch_expiry(&self) {
+        let inner = Arc::downgrade(&self.inner);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+
+                let result = tokio::task::spawn_blocking(move || inner.lock().expire()).await;
+                if let Err(error) = result.map_err(SchedulerErrors::from).and_then(|result| result) {
+                    error!("scheduler.fs_store.expire. {}", error);
+                }
+            }
+        });
+    }
+
+    // File IO blocks, so it runs outside of the async runtime
+    async fn run<T, F>(&self, operation: F) -> Result<T, SchedulerErrors>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Inner) -> Result<T, SchedulerErrors> + Send + 'static,
+    {
+        let inner = self.inner.clone();
 
-        Ok(Self { file })
+        tokio::task::spawn_blocking(move || operation(&mut inner.lock())).await?
     }
 }
 
+#[async_trait]
 impl Store for FileStore {
-    fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
-        let mut file = self.file.lock();
-        let mut reader = Reader::from_reader(&*file);
-
-        let mut results = HashMap::new();
-        for result in reader.deserialize() {
-            let record: Record = result?;
-            results.insert(record.id.clone(), record);
-        }
+    async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
+        self.run(|inner| {
+            let now = current_time();
+            let records = inner
+                .records
+                .iter()
+                .filter(|(_, record)| !is_expired(record.expires_at, now))
+                .map(|(id, record)| (id.clone(), record.clone()))
+                .collect();
+
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
+        let id = id.to_string();
+
+        self.run(move |inner| Ok(inner.get(&id).cloned())).await
+    }
+
+    async fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
+        self.run(move |inner| inner.write(Entry::Put(Box::new(record)))).await
+    }
+
+    async fn subscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        let (id, chat_id) = (id.to_string(), chat_id.to_string());
+
+        self.run(move |inner| {
+            inner.update(&id, |record| {
+                record.subscribers.insert(chat_id);
+            })
+        })
+        .await
+    }
+
+    async fn unsubscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        let (id, chat_id) = (id.to_string(), chat_id.to_string());
+
+        self.run(move |inner| match inner.get(&id) {
+            Some(record) if record.subscribers.contains(&chat_id) => inner.update(&id, |record| {
+                record.subscribers.remove(&chat_id);
+            }),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    async fn share(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        let (id, chat_id) = (id.to_string(), chat_id.to_string());
+
+        self.run(move |inner| {
+            inner.update(&id, |record| {
+                record.shared.insert(chat_id);
+            })
+        })
+        .await
+    }
+
+    async fn set_owner(&self, id: &str, owner: &str) -> Result<(), SchedulerErrors> {
+        let (id, owner) = (id.to_string(), owner.to_string());
+
+        self.run(move |inner| inner.update(&id, |record| record.owner = Some(owner)))
+            .await
+    }
+
+    async fn renew(&self, id: &str, expires_at: Option<u64>) -> Result<(), SchedulerErrors> {
+        let id = id.to_string();
+
+        self.run(move |inner| inner.update(&id, |record| record.expires_at = expires_at))
+            .await
+    }
+
+    async fn add_code(&self, code: &str, id: &str, expires_at: u64) -> Result<(), SchedulerErrors> {
+        let entry = Entry::AddCode {
+            code: code.to_string(),
+            id: id.to_string(),
+            expires_at,
+        };
+
+        self.run(move |inner| inner.write(entry)).await
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<String>, SchedulerErrors> {
+        let code = code.to_string();
+
+        self.run(move |inner| {
+            let id = match inner.codes.get(&code) {
+                Some((id, expires_at)) if *expires_at > current_time() => Some(id.clone()),
+                Some(_) => None,
+                None => return Ok(None),
+            };
+            inner.write(Entry::TakeCode { code })?;
+
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn codes(&self) -> Result<Vec<Code>, SchedulerErrors> {
+        self.run(|inner| {
+            let now = current_time();
+            let codes = inner
+                .codes
+                .iter()
+                .filter(|(_, (_, expires_at))| *expires_at > now)
+                .map(|(code, (id, expires_at))| Code {
+                    code: code.clone(),
+                    id: id.clone(),
+                    expires_at: *expires_at,
+                })
+                .collect();
+
+            Ok(codes)
+        })
+        .await
+    }
+
+    async fn set_value(&self, id: &str, value: &serde_json::Value) -> Result<(), SchedulerErrors> {
+        let (id, value) = (id.to_string(), value.clone());
 
-        file.seek(SeekFrom::Start(0))?;
+        self.run(move |inner| inner.update(&id, |record| record.last_value = Some(value)))
+            .await
+    }
+
+    async fn compare_and_set(&self, record: Record) -> Result<bool, SchedulerErrors> {
+        self.run(move |inner| {
+            let stored = match inner.get(&record.id) {
+                Some(stored) if stored.version != record.version => return Ok(false),
+                Some(stored) => stored,
+                None => return Err(SchedulerErrors::NotFound(record.id)),
+            };
+            let record = Record {
+                subscribers: stored.subscribers.clone(),
+                shared: stored.shared.clone(),
+                version: stored.version + 1,
+                ..record
+            };
+            inner.write(Entry::Put(Box::new(record)))?;
 
-        Ok(results)
+            Ok(true)
+        })
+        .await
     }
 
-    fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
-        let mut file = self.file.lock();
-        let mut reader = Reader::from_reader(&*file);
+    fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
 
-        let mut matching_record = None;
-        for result in reader.deserialize() {
-            let record: Record = result?;
+    async fn delete(&self, id: &str) -> Result<(), SchedulerErrors> {
+        let id = id.to_string();
 
-            if record.id == id {
-                matching_record = Some(record);
+        self.run(move |inner| {
+            if !inner.records.contains_key(&id) {
+                return Ok(());
             }
-        }
 
-        file.seek(SeekFrom::Start(0))?;
+            inner.write(Entry::Delete { id })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::record;
+    use serde_json::json;
+    use tempfile::TempDir;
 
-        Ok(matching_record)
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path).unwrap().lines().map(String::from).collect()
     }
 
-    fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
-        // CSV headers are needed only on initial write
-        let mut file = self.file.lock();
-        let file_size = file.metadata()?.len();
-        {
-            let mut writer = WriterBuilder::new().has_headers(file_size == 0).from_writer(&*file);
+    #[tokio::test]
+    async fn replays_log() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
 
-            writer.serialize(record)?;
-            writer.flush()?;
+        let store = FileStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        store.add(record("b")).await.unwrap();
+        store.subscribe("a", "chat").await.unwrap();
+        store.set_value("a", &json!({"price": 10})).await.unwrap();
+        store.delete("b").await.unwrap();
+        store.add_code("code", "a", current_time() + 60).await.unwrap();
+        drop(store);
+
+        let store = FileStore::new(&path).await.unwrap();
+        let records = store.load().await.unwrap();
+        assert_eq!(records.len(), 1);
+        let a = &records["a"];
+        assert_eq!(a.last_value, Some(json!({"price": 10})));
+        assert!(a.subscribers.contains("chat"));
+        assert_eq!(a.version, 2);
+        assert_eq!(store.take_code("code").await.unwrap(), Some(String::from("a")));
+    }
+
+    #[tokio::test]
+    async fn open_compacts() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
+
+        let store = FileStore::new(&path).await.unwrap();
+        let mut expired = record("expired");
+        expired.expires_at = Some(current_time() - 1);
+        store.add(expired).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        for value in 0..10 {
+            store.set_value("a", &json!(value)).await.unwrap();
         }
+        drop(store);
+        assert!(lines(&path).len() > 11);
 
-        file.seek(SeekFrom::Start(0))?;
-        Ok(())
+        let store = FileStore::new(&path).await.unwrap();
+        assert_eq!(lines(&path).len(), 1);
+        let record = store.get("a").await.unwrap().unwrap();
+        assert_eq!(record.last_value, Some(json!(9)));
+        assert!(store.get("expired").await.unwrap().is_none());
     }
 
-    fn delete(&self, _id: &str) -> Result<(), SchedulerErrors> {
-        todo!()
+    #[tokio::test]
+    async fn failed_compaction_keeps_writes() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
+
+        let store = FileStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        // The compacted log can't be created where a directory is in the way
+        fs::create_dir(path.with_extension("tmp")).unwrap();
+        for value in 0..=COMPACTION_THRESHOLD {
+            store.set_value("a", &json!(value)).await.unwrap();
+        }
+        drop(store);
+        fs::remove_dir(path.with_extension("tmp")).unwrap();
+
+        let store = FileStore::new(&path).await.unwrap();
+        let record = store.get("a").await.unwrap().unwrap();
+        assert_eq!(record.last_value, Some(json!(COMPACTION_THRESHOLD)));
+    }
+
+    #[tokio::test]
+    async fn skips_partial_last_line() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
+
+        let store = FileStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        drop(store);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"Put":{"id":"b","#).unwrap();
+
+        let store = FileStore::new(&path).await.unwrap();
+        assert_eq!(store.load().await.unwrap().len(), 1);
+        assert_eq!(lines(&path).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_torn_character() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
+
+        let store = FileStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        drop(store);
+        // The first byte of a two byte character
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"Put\":{\"id\":\"\xc3").unwrap();
+
+        let store = FileStore::new(&path).await.unwrap();
+        assert_eq!(store.load().await.unwrap().len(), 1);
+        assert_eq!(lines(&path).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn corrupt_line_fails() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.jsonl");
+
+        let store = FileStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        drop(store);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"qwerty\n").unwrap();
+        let entry = serde_json::to_string(&Entry::Put(Box::new(record("b")))).unwrap();
+        file.write_all(format!("{}\n", entry).as_bytes()).unwrap();
+
+        assert!(FileStore::new(&path).await.is_err());
+        // The log is left as it was, with the entries after the corrupt line
+        assert_eq!(lines(&path).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn compare_and_set_checks_version() {
+        let directory = TempDir::new().unwrap();
+        let store = FileStore::new(directory.path().join("records.jsonl")).await.unwrap();
+        store.add(record("a")).await.unwrap();
+
+        let mut stale = store.get("a").await.unwrap().unwrap();
+        store.set_value("a", &json!(1)).await.unwrap();
+        stale.url = String::from("https://example.org");
+        assert!(!store.compare_and_set(stale).await.unwrap());
+
+        let mut current = store.get("a").await.unwrap().unwrap();
+        current.url = String::from("https://example.org");
+        assert!(store.compare_and_set(current).await.unwrap());
+        assert_eq!(store.get("a").await.unwrap().unwrap().url, "https://example.org");
     }
 }
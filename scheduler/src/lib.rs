@@ -1,25 +1,122 @@
-// pub mod fs_store;
+pub mod cached_store;
+pub mod fs_store;
 pub mod redis_store;
+pub mod sql_store;
 pub mod store;
+pub mod transfer;
 
-use crate::store::Record;
-use broker::{Broker, Exchanges, Messages};
+use crate::store::{current_time, Event, Query, Record, Status};
+use broker::{Broker, Exchanges, Job, JobError, JobReply, JobResult, JobStatus, Messages};
 use log::{error, info};
 use parking_lot::Mutex;
-use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use store::Store;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 const INTERVAL_SECONDS: u64 = 1;
+const MINUTE_IN_SECONDS: u64 = 60;
+const HOUR_IN_SECONDS: u64 = 3_600;
+const DAY_IN_SECONDS: u64 = 86_400;
+// Attempts to update a job that keeps changing while it's being updated
+const UPDATE_ATTEMPTS: usize = 3;
+
+pub struct Config {
+    // How long before a record expires its subscribers get a warning
+    pub expiry_warning: Duration,
+    // How long a record (and its activation code) lives until someone activates it
+    pub pending_ttl: Duration,
+    // How often the schedule is compared with the store and corrected
+    pub reconcile_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            expiry_warning: Duration::from_secs(DAY_IN_SECONDS),
+            pending_ttl: Duration::from_secs(HOUR_IN_SECONDS),
+            reconcile_interval: Duration::from_secs(MINUTE_IN_SECONDS),
+        }
+    }
+}
+
+// Countdown until the next scrape of an active record
+struct Schedule {
+    interval: u64,
+    remaining: u64,
+    expires_at: Option<u64>,
+    // Whether the subscribers were already warned about the upcoming expiry
+    warned: bool,
+}
+
+impl Schedule {
+    fn new(record: &Record) -> Self {
+        Self {
+            interval: record.interval,
+            remaining: record.interval,
+            expires_at: record.expires_at,
+            warned: false,
+        }
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        self.interval == record.interval && self.expires_at == record.expires_at
+    }
+
+    // Takes the interval and expiry of the record, keeping the countdown if it can
+    fn update(&mut self, record: &Record) {
+        self.interval = record.interval;
+        self.remaining = self.remaining.min(record.interval);
+
+        if self.expires_at != record.expires_at {
+            self.expires_at = record.expires_at;
+            self.warned = false;
+        }
+    }
+}
+
+/// Corrections made to the schedule because it didn't match the store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileStats {
+    // Number of reconciliation passes
+    pub passes: u64,
+    // Records with subscribers that weren't scheduled
+    pub added: u64,
+    // Scheduled records that were gone from the store or had no subscribers left
+    pub removed: u64,
+    // Scheduled records with a different interval or expiry in the store
+    pub updated: u64,
+}
+
+#[derive(Default)]
+struct Corrections {
+    passes: AtomicU64,
+    added: AtomicU64,
+    removed: AtomicU64,
+    updated: AtomicU64,
+}
 
 #[derive(Debug)]
 pub enum SchedulerErrors {
     IO(std::io::Error),
-    CSV(csv::Error),
+    JSON(serde_json::Error),
+    SQL(rusqlite::Error),
     Redis(redis::RedisError),
     RuntimeJoin(tokio::task::JoinError),
     RuntimeSend(mpsc::error::SendError<redis_store::Command>),
     RuntimeReceive(oneshot::error::RecvError),
+    Forbidden { id: String, chat_id: String },
+    NotFound(String),
+    // The record kept changing while it was being updated
+    Conflict(String),
 }
 
 impl From<std::io::Error> for SchedulerErrors {
@@ -28,9 +125,15 @@ impl From<std::io::Error> for SchedulerErrors {
     }
 }
 
-impl From<csv::Error> for SchedulerErrors {
-    fn from(error: csv::Error) -> Self {
-        Self::CSV(error)
+impl From<serde_json::Error> for SchedulerErrors {
+    fn from(error: serde_json::Error) -> Self {
+        Self::JSON(error)
+    }
+}
+
+impl From<rusqlite::Error> for SchedulerErrors {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::SQL(error)
     }
 }
 
@@ -62,11 +165,15 @@ impl fmt::Display for SchedulerErrors {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::IO(error) => write!(f, "IO error. {}", error),
-            Self::CSV(error) => write!(f, "CSV error. {}", error),
+            Self::JSON(error) => write!(f, "JSON error. {}", error),
+            Self::SQL(error) => write!(f, "SQL error. {}", error),
             Self::Redis(error) => write!(f, "Redis error. {}", error),
             Self::RuntimeJoin(error) => write!(f, "Runtime join error. {}", error),
             Self::RuntimeSend(error) => write!(f, "Runtime send error. {}", error),
             Self::RuntimeReceive(error) => write!(f, "Runtime receive error. {}", error),
+            Self::Forbidden { id, chat_id } => write!(f, "Chat {} has no permission for record {}", chat_id, id),
+            Self::NotFound(id) => write!(f, "Record {} doesn't exist", id),
+            Self::Conflict(id) => write!(f, "Record {} kept changing", id),
         }
     }
 }
@@ -75,15 +182,62 @@ impl std::error::Error for SchedulerErrors {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Self::IO(error) => Some(error),
-            Self::CSV(error) => Some(error),
+            Self::JSON(error) => Some(error),
+            Self::SQL(error) => Some(error),
             Self::Redis(error) => Some(error),
             Self::RuntimeJoin(error) => Some(error),
             Self::RuntimeSend(error) => Some(error),
             Self::RuntimeReceive(error) => Some(error),
+            Self::Forbidden { .. } => None,
+            Self::NotFound(_) => None,
+            Self::Conflict(_) => None,
         }
     }
 }
 
+impl From<SchedulerErrors> for JobError {
+    fn from(error: SchedulerErrors) -> Self {
+        match error {
+            SchedulerErrors::NotFound(_) => JobError::NotFound,
+            SchedulerErrors::Forbidden { .. } => JobError::Forbidden,
+            SchedulerErrors::Conflict(_) => JobError::Conflict,
+            error => JobError::Internal(error.to_string()),
+        }
+    }
+}
+
+fn to_job(record: Record) -> Job {
+    let status = match record.status() {
+        Status::Pending => JobStatus::Pending,
+        Status::Active => JobStatus::Active,
+        Status::Paused => JobStatus::Paused,
+    };
+
+    Job {
+        id: record.id,
+        url: record.url,
+        script: record.script,
+        interval: record.interval,
+        owner: record.owner,
+        status,
+        subscribers: record.subscribers.len(),
+        ttl: record.ttl,
+        expires_at: record.expires_at,
+        last_value: record.last_value,
+    }
+}
+
+/// Scripts that return a boolean keep the old behaviour, a notification is sent on every `true`.
+/// Any other value is compared with the result of the previous run, and a notification is sent
+/// only if it has changed. null (or undefined in the script) never triggers a notification
+fn should_notify(previous: Option<&Value>, current: &Value) -> bool {
+    match current {
+        Value::Null | Value::Bool(false) => false,
+        Value::Bool(true) => true,
+        _ => previous != Some(current),
+    }
+}
+
 pub struct Scheduler<T, U>
 where
     T: Broker,
@@ -91,7 +245,9 @@ where
 {
     broker: Arc<T>,
     store: Arc<U>,
-    intervals: Arc<Mutex<HashMap<String, (Duration, u64)>>>,
+    intervals: Arc<Mutex<HashMap<String, Schedule>>>,
+    corrections: Arc<Corrections>,
+    config: Config,
 }
 
 impl<T, U> Scheduler<T, U>
@@ -99,16 +255,18 @@ where
     T: Broker + Sync + Send + 'static,
     U: Store + Sync + Send + 'static,
 {
-    pub async fn new(broker: T, store: U) -> Result<Self, SchedulerErrors> {
+    pub async fn new(broker: T, store: U, config: Config) -> Result<Self, SchedulerErrors> {
         let store = Arc::new(store);
         let broker = Arc::new(broker);
 
+        // Listen before loading, so nothing that changes in between is missed
+        let events = store.events();
         let mut records = store.load().await?;
 
         let mut intervals = HashMap::new();
         for (id, record) in records.drain() {
-            if record.chat_id.is_some() {
-                intervals.insert(id, (Duration::from_secs(record.interval), record.interval));
+            if !record.subscribers.is_empty() {
+                intervals.insert(id, Schedule::new(&record));
             }
         }
 
@@ -118,8 +276,12 @@ where
             broker,
             store,
             intervals,
+            corrections: Arc::new(Corrections::default()),
+            config,
         };
         scheduler.launch_interval();
+        scheduler.launch_events(events);
+        scheduler.launch_reconcile();
 
         Ok(scheduler)
     }
@@ -131,61 +293,234 @@ where
                 url,
                 interval,
                 script,
+                owner,
+                ttl,
+                code,
             } => {
+                // The record gets its real expiry only once it's activated
+                let expires_at = current_time() + self.config.pending_ttl.as_secs();
                 let record = Record {
-                    id,
+                    id: id.clone(),
                     url,
                     interval,
                     script,
-                    chat_id: None,
+                    subscribers: HashSet::new(),
+                    last_value: None,
+                    owner,
+                    shared: HashSet::new(),
+                    ttl,
+                    expires_at: Some(expires_at),
+                    version: 0,
+                    activated: Some(false),
+                };
+                self.store.add(record).await?;
+                self.store.add_code(&code, &id, expires_at).await?;
+            }
+            Messages::Activate { code, chat_id } => {
+                let record = match self.store.take_code(&code).await? {
+                    Some(id) => self.activate(&id, &chat_id).await?,
+                    None => None,
                 };
-                if let Err(error) = self.store.add(record).await {
-                    error!("scheduler.receive.Create. {}", error);
+                let record = match record {
+                    Some(record) => record,
+                    None => {
+                        self.reply(Messages::ActivationFailed { code, chat_id }).await;
+                        return Ok(());
+                    }
+                };
+
+                // Jobs created through the api keep their owner. the chat that activated one gets to
+                // manage its subscription and renew it, like the chats it's shared with
+                if !record.is_owner(&chat_id) {
+                    self.store.share(&record.id, &chat_id).await?;
                 }
+                self.store.subscribe(&record.id, &chat_id).await?;
+
+                self.intervals.lock().insert(record.id.clone(), Schedule::new(&record));
+
+                self.reply(Messages::Activated { id: record.id, chat_id }).await;
+            }
+            Messages::Subscribe { id, chat_id } => {
+                let record = match self.store.get(&id).await? {
+                    Some(record) => record,
+                    None => return Ok(()),
+                };
+
+                if !record.can_subscribe(&chat_id) {
+                    return self.forbid(id, chat_id).await;
+                }
+
+                self.store.subscribe(&id, &chat_id).await?;
+
+                // Another subscriber might have activated the record already. don't reset its countdown
+                let mut intervals = self.intervals.lock();
+                intervals
+                    .entry(record.id.clone())
+                    .or_insert_with(|| Schedule::new(&record));
             }
-            Messages::Activate { id, chat_id } => {
-                self.store.update(&id, &chat_id).await?;
+            Messages::Unsubscribe { id, chat_id } => {
+                self.store.unsubscribe(&id, &chat_id).await?;
 
+                // Stop scraping once nobody is interested in the results
                 match self.store.get(&id).await {
-                    Ok(record) => {
-                        if let Some(record) = record {
-                            let mut intervals = self.intervals.lock();
-                            intervals.insert(record.id, (Duration::from_secs(record.interval), record.interval));
+                    Ok(Some(record)) if !record.subscribers.is_empty() => {}
+                    Ok(_) => {
+                        let mut intervals = self.intervals.lock();
+                        intervals.remove(&id);
+                    }
+                    Err(error) => {
+                        error!("scheduler.receive.Unsubscribe. {}", error);
+                    }
+                }
+            }
+            Messages::List { chat_id } => {
+                let query = Query {
+                    chat_id: Some(chat_id.clone()),
+                    ..Query::default()
+                };
+
+                match self.store.query(&query, None, usize::MAX).await {
+                    Ok(page) => {
+                        let records = page.records.into_iter().map(|record| (record.url, record.id)).collect();
+                        let msg = Messages::ListResponse { records, chat_id };
+                        if let Err(error) = self.broker.publish(Exchanges::Bot, msg).await {
+                            error!("scheduler.receive.List.publish. {}", error);
                         }
                     }
                     Err(error) => {
-                        error!("scheduler.receive.Activate. {}", error);
+                        error!("scheduler.receive.List. {}", error);
                     }
                 }
             }
-            Messages::List { chat_id } => match self.store.load().await {
-                Ok(records) => {
-                    let records: Vec<(String, String)> = records
-                        .into_iter()
-                        .filter_map(|(id, record)| {
-                            if record.chat_id.is_some() && record.chat_id.unwrap() == chat_id {
-                                Some((record.url, id))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect();
-                    let msg = Messages::ListResponse { records, chat_id };
+            Messages::ScrapeResponse { id, value } => {
+                let record = match self.store.get(&id).await {
+                    Ok(Some(record)) => record,
+                    Ok(None) => {
+                        error!("scheduler.receive.ScrapeResponse.get.None");
+                        if self.intervals.lock().remove(&id).is_some() {
+                            self.corrections.removed.fetch_add(1, Ordering::Relaxed);
+                        }
+                        return Ok(());
+                    }
+                    Err(error) => {
+                        error!("scheduler.receive.ScrapeResponse.get. {}", error);
+                        return Ok(());
+                    }
+                };
+
+                self.store.set_value(&id, &value).await?;
+
+                if !should_notify(record.last_value.as_ref(), &value) {
+                    return Ok(());
+                }
+
+                for chat_id in record.subscribers {
+                    let msg = Messages::Notify {
+                        id: id.clone(),
+                        chat_id,
+                        url: record.url.clone(),
+                        previous: record.last_value.clone(),
+                        current: value.clone(),
+                    };
                     if let Err(error) = self.broker.publish(Exchanges::Bot, msg).await {
-                        error!("scheduler.receive.List.publish. {}", error);
+                        error!("scheduler.receive.ScrapeResponse.publish. {}", error);
                     }
                 }
-                Err(error) => {
-                    error!("scheduler.receive.List. {}", error);
+            }
+            Messages::Renew { id, chat_id } => {
+                let record = match self.store.get(&id).await? {
+                    Some(record) => record,
+                    None => return Ok(()),
+                };
+
+                if !record.can_subscribe(&chat_id) {
+                    return self.forbid(id, chat_id).await;
+                }
+                // The ttl starts counting once the record is activated
+                if !record.is_activated() {
+                    return Ok(());
                 }
-            },
-            Messages::Delete { id } => {
-                let mut intervals = self.intervals.lock();
-                intervals.remove(&id);
 
-                if let Err(error) = self.store.delete(&id).await {
-                    error!("scheduler.receive.Delete. {}", error);
+                self.renew(&record).await?;
+            }
+            Messages::Delete { id, chat_id } => {
+                match self.store.get(&id).await? {
+                    Some(record) if record.is_owner(&chat_id) => {}
+                    Some(_) => return self.forbid(id, chat_id).await,
+                    None => return Ok(()),
                 }
+
+                // The record keeps running if the store fails to delete it
+                self.store.delete(&id).await?;
+
+                self.intervals.lock().remove(&id);
+            }
+            Messages::Share { id, chat_id, user_id } => {
+                match self.store.get(&id).await? {
+                    Some(record) if record.is_owner(&chat_id) => {}
+                    Some(_) => return self.forbid(id, chat_id).await,
+                    None => return Ok(()),
+                }
+
+                self.store.share(&id, &user_id).await?;
+            }
+            Messages::Transfer { id, chat_id, user_id } => {
+                match self.store.get(&id).await? {
+                    Some(record) if record.is_owner(&chat_id) => {}
+                    Some(_) => return self.forbid(id, chat_id).await,
+                    None => return Ok(()),
+                }
+
+                // The previous owner keeps read only access, so an existing subscription stays valid
+                self.store.set_owner(&id, &user_id).await?;
+                self.store.share(&id, &chat_id).await?;
+            }
+            Messages::GetJob { request_id, id, owner } => {
+                let result = self.owned_job(&id, owner.as_deref()).await;
+                let result = result.map(|record| JobReply::Job(to_job(record)));
+                self.respond(request_id, result).await;
+            }
+            Messages::ListJobs {
+                request_id,
+                owner,
+                cursor,
+                limit,
+            } => {
+                let query = Query {
+                    owner: Some(owner),
+                    ..Query::default()
+                };
+                let result = match self.store.query(&query, cursor.as_deref(), limit).await {
+                    Ok(page) => Ok(JobReply::Jobs {
+                        jobs: page.records.into_iter().map(to_job).collect(),
+                        cursor: page.cursor,
+                    }),
+                    Err(error) => Err(error.into()),
+                };
+                self.respond(request_id, result).await;
+            }
+            Messages::UpdateJob {
+                request_id,
+                id,
+                owner,
+                url,
+                script,
+                interval,
+            } => {
+                let result = self.update_job(&id, owner.as_deref(), url, script, interval).await;
+                self.respond(request_id, result).await;
+            }
+            Messages::DeleteJob { request_id, id, owner } => {
+                let result = self.delete_job(&id, owner.as_deref()).await;
+                self.respond(request_id, result).await;
+            }
+            Messages::RunJob { request_id, id, owner } => {
+                let result = self.run_job(&id, owner.as_deref()).await;
+                self.respond(request_id, result).await;
+            }
+            Messages::RenewJob { request_id, id, owner } => {
+                let result = self.renew_job(&id, owner.as_deref()).await;
+                self.respond(request_id, result).await;
             }
             _ => {}
         }
@@ -193,10 +528,290 @@ where
         Ok(())
     }
 
+    // Marks the record as activated and starts the countdown of its ttl. None if the record is gone
+    async fn activate(&self, id: &str, chat_id: &str) -> Result<Option<Record>, SchedulerErrors> {
+        for _ in 0..UPDATE_ATTEMPTS {
+            let mut record = match self.store.get(id).await? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+
+            // The code is the proof of being the creator. claim the record unless the api already
+            // assigned an owner to it
+            if record.owner.is_none() {
+                record.owner = Some(chat_id.to_string());
+            }
+            record.activated = Some(true);
+            record.expires_at = record.ttl.map(|ttl| current_time() + ttl);
+
+            if self.store.compare_and_set(record.clone()).await? {
+                return Ok(Some(record));
+            }
+        }
+
+        Err(SchedulerErrors::Conflict(id.to_string()))
+    }
+
+    // The job if it exists and belongs to the owner. None allows any job
+    async fn owned_job(&self, id: &str, owner: Option<&str>) -> Result<Record, JobError> {
+        let record = self.store.get(id).await?.ok_or(JobError::NotFound)?;
+
+        match owner {
+            Some(owner) if !record.is_owner(owner) => Err(JobError::Forbidden),
+            _ => Ok(record),
+        }
+    }
+
+    async fn update_job(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        url: Option<String>,
+        script: Option<String>,
+        interval: Option<u64>,
+    ) -> JobResult {
+        for _ in 0..UPDATE_ATTEMPTS {
+            let mut record = self.owned_job(id, owner).await?;
+            if let Some(url) = &url {
+                record.url = url.clone();
+            }
+            if let Some(script) = &script {
+                record.script = script.clone();
+            }
+            if let Some(interval) = interval {
+                record.interval = interval;
+            }
+
+            if !self.store.compare_and_set(record.clone()).await? {
+                continue;
+            }
+
+            if let Some(schedule) = self.intervals.lock().get_mut(id) {
+                schedule.update(&record);
+            }
+
+            return Ok(JobReply::Job(to_job(record)));
+        }
+
+        Err(JobError::Conflict)
+    }
+
+    async fn delete_job(&self, id: &str, owner: Option<&str>) -> JobResult {
+        self.owned_job(id, owner).await?;
+        self.store.delete(id).await?;
+
+        self.intervals.lock().remove(id);
+
+        Ok(JobReply::Done)
+    }
+
+    async fn run_job(&self, id: &str, owner: Option<&str>) -> JobResult {
+        let record = self.owned_job(id, owner).await?;
+        let message = Messages::Scrape {
+            id: record.id,
+            url: record.url,
+            script: record.script,
+        };
+        self.broker
+            .publish(Exchanges::Scraper, message)
+            .await
+            .map_err(|error| JobError::Internal(error.to_string()))?;
+
+        // The next scheduled run is an interval away from this one
+        if let Some(schedule) = self.intervals.lock().get_mut(id) {
+            schedule.remaining = schedule.interval;
+        }
+
+        Ok(JobReply::Done)
+    }
+
+    async fn renew_job(&self, id: &str, owner: Option<&str>) -> JobResult {
+        let record = self.owned_job(id, owner).await?;
+        if !record.is_activated() {
+            return Err(JobError::NotActivated);
+        }
+        self.renew(&record).await?;
+
+        Ok(JobReply::Done)
+    }
+
+    // Restarts the countdown of the record's ttl
+    async fn renew(&self, record: &Record) -> Result<(), SchedulerErrors> {
+        let expires_at = record.ttl.map(|ttl| current_time() + ttl);
+        self.store.renew(&record.id, expires_at).await?;
+
+        if let Some(schedule) = self.intervals.lock().get_mut(&record.id) {
+            schedule.expires_at = expires_at;
+            schedule.warned = false;
+        }
+
+        Ok(())
+    }
+
+    async fn respond(&self, request_id: String, result: JobResult) {
+        let message = Messages::JobResponse { request_id, result };
+
+        if let Err(error) = self.broker.publish(Exchanges::Api, message).await {
+            error!("scheduler.respond.publish. {}", error);
+        }
+    }
+
+    pub fn reconcile_stats(&self) -> ReconcileStats {
+        ReconcileStats {
+            passes: self.corrections.passes.load(Ordering::Relaxed),
+            added: self.corrections.added.load(Ordering::Relaxed),
+            removed: self.corrections.removed.load(Ordering::Relaxed),
+            updated: self.corrections.updated.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn reply(&self, message: Messages) {
+        if let Err(error) = self.broker.publish(Exchanges::Bot, message).await {
+            error!("scheduler.reply.publish. {}", error);
+        }
+    }
+
+    async fn forbid(&self, id: String, chat_id: String) -> Result<(), SchedulerErrors> {
+        let msg = Messages::Forbidden {
+            id: id.clone(),
+            chat_id: chat_id.clone(),
+        };
+        self.reply(msg).await;
+
+        Err(SchedulerErrors::Forbidden { id, chat_id })
+    }
+
+    // Keeps the schedule in line with changes that don't go through receive, like expiry or
+    // deletions made by other processes sharing the store
+    fn launch_events(&self, mut events: broadcast::Receiver<Event>) {
+        let broker = Arc::clone(&self.broker);
+        let intervals = Arc::clone(&self.intervals);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::RecvError::Lagged(skipped)) => {
+                        error!("scheduler.launch_events.lagged. skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(broadcast::RecvError::Closed) => return,
+                };
+
+                match event {
+                    Event::Deleted { id } => {
+                        intervals.lock().remove(&id);
+                    }
+                    Event::Expired(record) => {
+                        intervals.lock().remove(&record.id);
+
+                        for chat_id in record.subscribers.iter() {
+                            let message = Messages::Expired {
+                                id: record.id.clone(),
+                                chat_id: chat_id.clone(),
+                                url: record.url.clone(),
+                            };
+
+                            if let Err(error) = broker.publish(Exchanges::Bot, message).await {
+                                error!("scheduler.launch_events.expired.publish. {}", error);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    // Changes made to the store and the schedule together can fail half way, or the store can
+    // change under the scheduler. every once in a while the schedule is rebuilt from the store
+    fn launch_reconcile(&self) {
+        let store = Arc::clone(&self.store);
+        let intervals = Arc::clone(&self.intervals);
+        let corrections = Arc::clone(&self.corrections);
+        let period = self.config.reconcile_interval;
+
+        tokio::spawn(async move {
+            // The schedule was just loaded from the store
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(error) = Self::reconcile(&*store, &intervals, &corrections).await {
+                    error!("scheduler.reconcile. {}", error);
+                }
+            }
+        });
+    }
+
+    async fn reconcile(
+        store: &U,
+        intervals: &Mutex<HashMap<String, Schedule>>,
+        corrections: &Corrections,
+    ) -> Result<(), SchedulerErrors> {
+        let records = store.load().await?;
+
+        let mismatches: Vec<String> = {
+            let intervals = intervals.lock();
+            let stale = intervals.iter().filter(|(id, schedule)| match records.get(*id) {
+                Some(record) => record.subscribers.is_empty() || !schedule.matches(record),
+                None => true,
+            });
+            let missing = records
+                .values()
+                .filter(|record| !record.subscribers.is_empty() && !intervals.contains_key(&record.id));
+
+            stale
+                .map(|(id, _)| id.clone())
+                .chain(missing.map(|record| record.id.clone()))
+                .collect()
+        };
+
+        let (mut added, mut removed, mut updated) = (0, 0, 0);
+        for id in mismatches.iter() {
+            // The snapshot can be older than a change made while it was loaded. only what the store
+            // has right now is trusted
+            let record = store.get(id).await?.filter(|record| !record.subscribers.is_empty());
+
+            let mut intervals = intervals.lock();
+            match (record, intervals.get_mut(id)) {
+                (None, Some(_)) => {
+                    intervals.remove(id);
+                    removed += 1;
+                }
+                (Some(record), None) => {
+                    intervals.insert(id.clone(), Schedule::new(&record));
+                    added += 1;
+                }
+                (Some(record), Some(schedule)) if !schedule.matches(&record) => {
+                    schedule.update(&record);
+                    updated += 1;
+                }
+                _ => {}
+            }
+        }
+
+        corrections.passes.fetch_add(1, Ordering::Relaxed);
+        corrections.added.fetch_add(added, Ordering::Relaxed);
+        corrections.removed.fetch_add(removed, Ordering::Relaxed);
+        corrections.updated.fetch_add(updated, Ordering::Relaxed);
+        if added + removed + updated > 0 {
+            info!(
+                "Reconciled the schedule with the store. added: {}. removed: {}. updated: {}",
+                added, removed, updated
+            );
+        }
+
+        Ok(())
+    }
+
     fn launch_interval(&self) {
         let broker = Arc::clone(&self.broker);
         let store = Arc::clone(&self.store);
         let intervals = Arc::clone(&self.intervals);
+        let corrections = Arc::clone(&self.corrections);
+        let expiry_warning = self.config.expiry_warning.as_secs();
 
         tokio::spawn(async move {
             let period = Duration::from_secs(INTERVAL_SECONDS);
@@ -204,18 +819,35 @@ where
 
             loop {
                 interval.tick().await;
+                let now = current_time();
 
                 let mut ids = Vec::new();
-                for (id, (duration, current_duration)) in intervals.lock().iter_mut() {
-                    info!("id: {}. current_duration: {}", id, current_duration);
+                let mut expiring = Vec::new();
+                {
+                    let mut intervals = intervals.lock();
 
-                    if *current_duration == 0 {
-                        *current_duration = duration.as_secs();
-                        ids.push(id.clone());
-                        continue;
-                    }
+                    // The store removes expired records by itself. just stop scheduling them
+                    intervals
+                        .retain(|_, schedule| !matches!(schedule.expires_at, Some(expires_at) if expires_at <= now));
 
-                    *current_duration -= 1;
+                    for (id, schedule) in intervals.iter_mut() {
+                        info!("id: {}. remaining: {}", id, schedule.remaining);
+
+                        if let Some(expires_at) = schedule.expires_at {
+                            if !schedule.warned && expires_at - now <= expiry_warning {
+                                schedule.warned = true;
+                                expiring.push(id.clone());
+                            }
+                        }
+
+                        if schedule.remaining == 0 {
+                            schedule.remaining = schedule.interval;
+                            ids.push(id.clone());
+                            continue;
+                        }
+
+                        schedule.remaining -= 1;
+                    }
                 }
 
                 for id in ids.iter() {
@@ -224,7 +856,6 @@ where
                             if let Some(record) = record {
                                 let message = Messages::Scrape {
                                     id: record.id,
-                                    chat_id: record.chat_id,
                                     url: record.url,
                                     script: record.script,
                                 };
@@ -233,7 +864,11 @@ where
                                     error!("scheduler.launch_interval.publish. {}", error);
                                 }
                             } else {
+                                // Gone from the store, so there's nothing left to scrape
                                 error!("scheduler.launch_interval.get.None");
+                                if intervals.lock().remove(id).is_some() {
+                                    corrections.removed.fetch_add(1, Ordering::Relaxed);
+                                }
                             }
                         }
                         Err(error) => {
@@ -241,7 +876,187 @@ where
                         }
                     }
                 }
+
+                for id in expiring.iter() {
+                    let record = match store.get(id).await {
+                        Ok(Some(record)) => record,
+                        Ok(None) => continue,
+                        Err(error) => {
+                            error!("scheduler.launch_interval.expiring.get. {}", error);
+                            continue;
+                        }
+                    };
+
+                    for chat_id in record.subscribers {
+                        let message = Messages::Expiring {
+                            id: record.id.clone(),
+                            chat_id,
+                            url: record.url.clone(),
+                            expires_at: record.expires_at.unwrap_or_default(),
+                        };
+
+                        if let Err(error) = broker.publish(Exchanges::Bot, message).await {
+                            error!("scheduler.launch_interval.expiring.publish. {}", error);
+                        }
+                    }
+                }
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fs_store::FileStore, store::tests::record};
+    use async_trait::async_trait;
+    use broker::{BrokerErrors, Consumer};
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    struct NullBroker;
+
+    #[async_trait]
+    impl Broker for NullBroker {
+        async fn publish(&self, _exchange: Exchanges, _message: Messages) -> Result<(), BrokerErrors> {
+            Ok(())
+        }
+
+        async fn subscribe(&self, _exchange: Exchanges) -> Result<Consumer, BrokerErrors> {
+            Err(BrokerErrors::Custom(String::from("Not supported")))
+        }
+    }
+
+    // A scheduler without its background tasks, so the schedule changes only when a test says so
+    fn scheduler(store: FileStore, scheduled: Vec<Record>) -> Scheduler<NullBroker, FileStore> {
+        let intervals = scheduled
+            .iter()
+            .map(|record| (record.id.clone(), Schedule::new(record)))
+            .collect();
+
+        Scheduler {
+            broker: Arc::new(NullBroker),
+            store: Arc::new(store),
+            intervals: Arc::new(Mutex::new(intervals)),
+            corrections: Arc::new(Corrections::default()),
+            config: Config::default(),
+        }
+    }
+
+    fn subscribed(id: &str) -> Record {
+        let mut record = record(id);
+        record.subscribers.insert(String::from("chat"));
+        record
+    }
+
+    #[test]
+    fn notifies_on_true() {
+        assert!(should_notify(None, &Value::Bool(true)));
+        assert!(should_notify(Some(&Value::Bool(true)), &Value::Bool(true)));
+        assert!(!should_notify(None, &Value::Bool(false)));
+        assert!(!should_notify(Some(&json!(1)), &Value::Null));
+    }
+
+    #[test]
+    fn notifies_on_change() {
+        assert!(should_notify(None, &json!({"price": 10})));
+        assert!(should_notify(Some(&json!({"price": 10})), &json!({"price": 11})));
+        assert!(!should_notify(Some(&json!({"price": 10})), &json!({"price": 10})));
+        assert!(should_notify(Some(&json!("1")), &json!(1)));
+    }
+
+    #[tokio::test]
+    async fn renew_from_activating_chat() {
+        let directory = TempDir::new().unwrap();
+        let store = FileStore::new(directory.path().join("records.jsonl")).await.unwrap();
+        let scheduler = scheduler(store, Vec::new());
+
+        let create = Messages::Create {
+            id: String::from("a"),
+            url: String::from("https://example.com"),
+            interval: 5,
+            script: String::from("return document.title"),
+            owner: Some(String::from("api:owner")),
+            ttl: Some(60),
+            code: String::from("code"),
+        };
+        scheduler.receive(create).await.unwrap();
+        let activate = Messages::Activate {
+            code: String::from("code"),
+            chat_id: String::from("chat"),
+        };
+        scheduler.receive(activate).await.unwrap();
+
+        let record = scheduler.store.get("a").await.unwrap().unwrap();
+        assert_eq!(record.owner.as_deref(), Some("api:owner"));
+        assert!(record.subscribers.contains("chat"));
+
+        let renew = Messages::Renew {
+            id: String::from("a"),
+            chat_id: String::from("chat"),
+        };
+        scheduler.receive(renew).await.unwrap();
+
+        let renew = Messages::Renew {
+            id: String::from("a"),
+            chat_id: String::from("other"),
+        };
+        assert!(scheduler.receive(renew).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reconcile_corrects_schedule() {
+        let directory = TempDir::new().unwrap();
+        let store = FileStore::new(directory.path().join("records.jsonl")).await.unwrap();
+        let mut changed = subscribed("changed");
+        store.add(changed.clone()).await.unwrap();
+        store.add(subscribed("missing")).await.unwrap();
+        store.add(record("unsubscribed")).await.unwrap();
+        store.add(subscribed("scheduled")).await.unwrap();
+
+        changed.interval = 60;
+        let scheduler = scheduler(store, vec![changed, subscribed("deleted"), subscribed("scheduled")]);
+        Scheduler::<NullBroker, FileStore>::reconcile(&*scheduler.store, &scheduler.intervals, &scheduler.corrections)
+            .await
+            .unwrap();
+
+        let expected = ReconcileStats {
+            passes: 1,
+            added: 1,
+            removed: 1,
+            updated: 1,
+        };
+        assert_eq!(scheduler.reconcile_stats(), expected);
+
+        let intervals = scheduler.intervals.lock();
+        let mut ids: Vec<&str> = intervals.keys().map(String::as_str).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["changed", "missing", "scheduled"]);
+        assert_eq!(intervals["changed"].interval, 5);
+    }
+
+    #[tokio::test]
+    async fn reconcile_keeps_matching_schedule() {
+        let directory = TempDir::new().unwrap();
+        let store = FileStore::new(directory.path().join("records.jsonl")).await.unwrap();
+        store.add(subscribed("a")).await.unwrap();
+
+        let scheduler = scheduler(store, vec![subscribed("a")]);
+        for _ in 0..2 {
+            Scheduler::<NullBroker, FileStore>::reconcile(
+                &*scheduler.store,
+                &scheduler.intervals,
+                &scheduler.corrections,
+            )
+            .await
+            .unwrap();
+        }
+
+        let expected = ReconcileStats {
+            passes: 2,
+            ..ReconcileStats::default()
+        };
+        assert_eq!(scheduler.reconcile_stats(), expected);
+        assert!(scheduler.intervals.lock().contains_key("a"));
+    }
+}
@@ -1,331 +1,1955 @@
-use crate::store::Record;
+use crate::store::{current_time, Code, Event, Events, Page, Query, Record, Status, EXPIRY_CHECK_INTERVAL};
 use crate::{SchedulerErrors, Store};
 use async_trait::async_trait;
 use log::{error, info};
-use redis::aio::Connection;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, time::Duration};
+use parking_lot::RwLock;
+use redis::{aio::MultiplexedConnection, ErrorKind, FromRedisValue, RedisResult, RedisWrite, ToRedisArgs, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::{
+    broadcast,
     mpsc::{self, Sender},
-    oneshot,
+    oneshot, Notify,
 };
 
-const MONTH_IN_SECONDS: u64 = 2_628_000;
+const CONNECT_ATTEMPTS: usize = 5;
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// Number of records fetched at once by a query
+const QUERY_BATCH: usize = 100;
+// Keys of expired records are kept this much longer, so the store that removes them can still
+// report what they were
+const EXPIRY_GRACE: u64 = 3_600;
+// Approximate number of events kept in the stream
+const EVENTS_STREAM_LENGTH: usize = 10_000;
+// How long a read of the events stream waits for new events, in milliseconds
+const EVENTS_BLOCK: u64 = 5_000;
+
+// Sets fields of an existing record and increments its version. Returns the previous values of the
+// fields, or nil when the record doesn't exist
+const UPDATE_FIELDS: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return false
+end
+local previous = {}
+for i = 1, #ARGV, 2 do
+    previous[#previous + 1] = redis.call('HGET', KEYS[1], ARGV[i]) or ''
+end
+if #ARGV > 0 then
+    redis.call('HSET', KEYS[1], unpack(ARGV))
+end
+redis.call('HINCRBY', KEYS[1], 'version', 1)
+return previous
+";
+
+// Replaces the fields of a record if its version is still ARGV[1]. Returns the status, -1 when the
+// record doesn't exist, 0 when the version changed and 1 when it was replaced, and the previous owner
+const COMPARE_AND_SET: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return {-1, ''}
+end
+local version = tonumber(redis.call('HGET', KEYS[1], 'version') or '0')
+if version ~= tonumber(ARGV[1]) then
+    return {0, ''}
+end
+local owner = redis.call('HGET', KEYS[1], 'owner') or ''
+redis.call('HSET', KEYS[1], unpack(ARGV, 2))
+redis.call('HSET', KEYS[1], 'version', version + 1)
+return {1, owner}
+";
+
+// Takes up to ARGV[2] ids that expired by ARGV[1] out of the ids set. each of them is taken by a
+// single store, which reports and removes the record
+const TAKE_EXPIRED: &str = r"
+local ids = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+if #ids > 0 then
+    redis.call('ZREM', KEYS[1], unpack(ids))
+end
+return ids
+";
+
+// Moves a record to the status index it belongs to, out of the other two, from what's stored. Records
+// stored before the activated field existed were activated once they had an owner. KEYS are the
+// record, its subscribers and the pending, active and paused indexes, ARGV[1] is the id
+const INDEX_STATUS: &str = r"
+local index = nil
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    local activated = redis.call('HGET', KEYS[1], 'activated')
+    if not activated then
+        activated = (redis.call('HGET', KEYS[1], 'owner') or '') ~= '' and '1' or '0'
+    end
+    if activated ~= '1' then
+        index = KEYS[3]
+    elseif redis.call('SCARD', KEYS[2]) > 0 then
+        index = KEYS[4]
+    else
+        index = KEYS[5]
+    end
+end
+for i = 3, 5 do
+    if KEYS[i] ~= index then
+        redis.call('ZREM', KEYS[i], ARGV[1])
+    end
+end
+if index then
+    redis.call('ZADD', index, 0, ARGV[1])
+end
+";
+
+pub const DEFAULT_NAMESPACE: &str = "notifier";
+// Version of the key layout. 0 is the layout from before namespaces, where records were stored
+// under their bare ids, and 1 the one before the status indexes
+const SCHEMA_VERSION: u64 = 2;
+const STATUSES: [Status; 3] = [Status::Pending, Status::Active, Status::Paused];
+
+/// Names of the keys of a store. All of them start with the namespace, so the store doesn't collide
+/// with other data in the same database and several deployments can share one Redis
+#[derive(Debug, Clone)]
+pub struct Keys {
+    namespace: String,
+}
+
+impl Keys {
+    pub fn new(namespace: &str) -> Self {
+        Keys {
+            namespace: namespace.to_string(),
+        }
+    }
+
+    // Version of the key layout the data is stored in
+    fn schema(&self) -> String {
+        format!("{}:schema", self.namespace)
+    }
+
+    // Sorted set of the ids of all the records, scored by their expiry
+    fn ids(&self) -> String {
+        format!("{}:ids", self.namespace)
+    }
+
+    fn record(&self, id: &str) -> String {
+        format!("{}:record:{}", self.namespace, id)
+    }
+
+    // The chat ids subscribed to a record are kept in a set next to the record's hash
+    fn subscribers(&self, id: &str) -> String {
+        format!("{}:record:{}:subscribers", self.namespace, id)
+    }
+
+    // Chat ids the owner shared the record with
+    fn shared(&self, id: &str) -> String {
+        format!("{}:record:{}:shared", self.namespace, id)
+    }
+
+    // Secondary index with the ids of the records a chat is subscribed to
+    fn chat(&self, chat_id: &str) -> String {
+        format!("{}:chat:{}", self.namespace, chat_id)
+    }
+
+    // Secondary index with the ids of the records of an owner
+    fn owner(&self, owner: &str) -> String {
+        format!("{}:owner:{}", self.namespace, owner)
+    }
+
+    // Sorted set with the ids of the records in a status. all the scores are 0, so the ids are
+    // ordered by themselves and can be paged through with ZRANGEBYLEX
+    fn status(&self, status: Status) -> String {
+        let name = match status {
+            Status::Pending => "pending",
+            Status::Active => "active",
+            Status::Paused => "paused",
+        };
+
+        format!("{}:status:{}", self.namespace, name)
+    }
+
+    fn code(&self, code: &str) -> String {
+        format!("{}:code:{}", self.namespace, code)
+    }
+
+    // Stream of the changes of the records, read by every store of the namespace
+    fn events(&self) -> String {
+        format!("{}:events", self.namespace)
+    }
+}
+
+// Score of a record in the ids sorted set. records that never expire stay there forever
+fn score(expires_at: Option<u64>) -> String {
+    match expires_at {
+        Some(expires_at) => expires_at.to_string(),
+        None => String::from("+inf"),
+    }
+}
+
+fn expire(pipeline: &mut redis::Pipeline, key: &str, expires_at: Option<u64>) {
+    match expires_at {
+        Some(expires_at) => pipeline.cmd("EXPIREAT").arg(key).arg(expires_at + EXPIRY_GRACE),
+        None => pipeline.cmd("PERSIST").arg(key),
+    };
+}
+
+// Optional fields are stored as empty strings
+fn optional_to_string(value: Option<u64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn parse_number(field: &'static str, value: &str) -> RedisResult<u64> {
+    value
+        .parse::<u64>()
+        .map_err(|_| (ErrorKind::TypeError, "Record field is not a number", field.to_string()).into())
+}
+
+fn parse_optional(field: &'static str, value: Option<String>) -> RedisResult<Option<u64>> {
+    match value.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => parse_number(field, value).map(Some),
+    }
+}
+
+/// Converts the reply of HGETALL on a record's hash. The subscribers and shared sets are stored
+/// in separate keys, so they are left empty
+impl FromRedisValue for Record {
+    fn from_redis_value(value: &Value) -> RedisResult<Self> {
+        let mut fields = HashMap::<String, String>::from_redis_value(value)?;
+        let mut take = |field: &'static str| -> RedisResult<String> {
+            fields
+                .remove(field)
+                .ok_or_else(|| (ErrorKind::TypeError, "Record field is missing", field.to_string()).into())
+        };
+
+        let id = take("id")?;
+        let interval = parse_number("interval", &take("interval")?)?;
+        let script = take("script")?;
+        let url = take("url")?;
+        let last_value = take("last_value").ok();
+        let owner = take("owner").ok().filter(|owner| !owner.is_empty());
+        let ttl = parse_optional("ttl", take("ttl").ok())?;
+        let expires_at = parse_optional("expires_at", take("expires_at").ok())?;
+        let version = parse_optional("version", take("version").ok())?;
+        // Missing from the hashes stored before the flag existed
+        let activated = take("activated").ok().map(|activated| activated == "1");
+
+        Ok(Record {
+            id,
+            interval,
+            script,
+            url,
+            subscribers: HashSet::new(),
+            last_value: last_value.and_then(|value| serde_json::from_str(&value).ok()),
+            owner,
+            shared: HashSet::new(),
+            ttl,
+            expires_at,
+            version: version.unwrap_or_default(),
+            activated,
+        })
+    }
+}
+
+/// Writes the fields of a record's hash as field/value pairs, to be used with HSET
+impl ToRedisArgs for Record {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        let last_value = self
+            .last_value
+            .as_ref()
+            .map(|value| value.to_string())
+            .unwrap_or_default();
+        let fields = [
+            ("id", self.id.clone()),
+            ("url", self.url.clone()),
+            ("interval", self.interval.to_string()),
+            ("script", self.script.clone()),
+            ("last_value", last_value),
+            ("owner", self.owner.clone().unwrap_or_default()),
+            ("ttl", optional_to_string(self.ttl)),
+            ("expires_at", optional_to_string(self.expires_at)),
+            ("version", self.version.to_string()),
+            ("activated", String::from(if self.is_activated() { "1" } else { "0" })),
+        ];
+
+        for (field, value) in fields.iter() {
+            out.write_arg(field.as_bytes());
+            out.write_arg(value.as_bytes());
+        }
+    }
+}
+
+fn query_record(pipeline: &mut redis::Pipeline, keys: &Keys, id: &str) {
+    pipeline
+        .cmd("HGETALL")
+        .arg(keys.record(id))
+        .cmd("SMEMBERS")
+        .arg(keys.subscribers(id))
+        .cmd("SMEMBERS")
+        .arg(keys.shared(id));
+}
+
+// Replies of the commands added by query_record
+type RecordReply = (Value, HashSet<String>, HashSet<String>);
+
+// None if the record's hash doesn't exist
+fn record_from_reply((hash, subscribers, shared): RecordReply) -> RedisResult<Option<Record>> {
+    if let Value::Bulk(ref fields) = hash {
+        if fields.is_empty() {
+            return Ok(None);
+        }
+    }
+
+    let mut record = Record::from_redis_value(&hash)?;
+    record.subscribers = subscribers;
+    record.shared = shared;
+
+    Ok(Some(record))
+}
+
+// An entry of the events stream, its id and fields
+type StreamEntry = (String, HashMap<String, String>);
+
+// Appends an event to the stream every store of the namespace reads
+async fn publish(connection: &mut MultiplexedConnection, keys: &Keys, event: Event) -> Result<(), SchedulerErrors> {
+    redis::cmd("XADD")
+        .arg(keys.events())
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(EVENTS_STREAM_LENGTH)
+        .arg("*")
+        .arg("event")
+        .arg(serde_json::to_string(&event)?)
+        .query_async::<_, ()>(connection)
+        .await?;
+
+    Ok(())
+}
+
+// Id of the last event in the stream, so that only the events after it are read
+async fn last_event_id(connection: &mut redis::aio::Connection, keys: &Keys) -> Result<String, SchedulerErrors> {
+    let last: Vec<StreamEntry> = redis::cmd("XREVRANGE")
+        .arg(keys.events())
+        .arg("+")
+        .arg("-")
+        .arg("COUNT")
+        .arg(1)
+        .query_async(connection)
+        .await?;
+
+    Ok(last
+        .into_iter()
+        .next()
+        .map(|(id, _)| id)
+        .unwrap_or_else(|| String::from("0-0")))
+}
+
+// Waits for the events after last_id and moves it past the ones read
+async fn read_events(
+    connection: &mut redis::aio::Connection,
+    keys: &Keys,
+    last_id: &mut String,
+) -> Result<Vec<Event>, SchedulerErrors> {
+    let reply: Option<Vec<(String, Vec<StreamEntry>)>> = redis::cmd("XREAD")
+        .arg("COUNT")
+        .arg(QUERY_BATCH)
+        .arg("BLOCK")
+        .arg(EVENTS_BLOCK)
+        .arg("STREAMS")
+        .arg(keys.events())
+        .arg(last_id.as_str())
+        .query_async(connection)
+        .await?;
+
+    let mut events = Vec::new();
+    for (_, entries) in reply.unwrap_or_default() {
+        for (id, fields) in entries {
+            let event = fields.get("event").map(|event| serde_json::from_str::<Event>(event));
+            match event {
+                Some(Ok(event)) => events.push(event),
+                Some(Err(error)) => error!("scheduler.redis_store.read_events. id: {}. {}", id, error),
+                None => error!("scheduler.redis_store.read_events. id: {}. no event", id),
+            }
+            *last_id = id;
+        }
+    }
+
+    Ok(events)
+}
+
+type Reply<T> = oneshot::Sender<Result<T, SchedulerErrors>>;
 
 #[derive(Debug)]
 pub enum Command {
     Load {
-        sender_once: oneshot::Sender<Vec<Record>>,
+        sender_once: Reply<Vec<Record>>,
     },
     Add {
-        record: Record,
+        record: Box<Record>,
+        sender_once: Reply<()>,
+    },
+    Subscribe {
+        id: String,
+        chat_id: String,
+        sender_once: Reply<()>,
+    },
+    Unsubscribe {
+        id: String,
+        chat_id: String,
+        sender_once: Reply<()>,
     },
-    Activate {
+    Share {
         id: String,
         chat_id: String,
+        sender_once: Reply<()>,
+    },
+    SetOwner {
+        id: String,
+        owner: String,
+        sender_once: Reply<()>,
+    },
+    Renew {
+        id: String,
+        expires_at: Option<u64>,
+        sender_once: Reply<()>,
+    },
+    AddCode {
+        code: String,
+        id: String,
+        expires_at: u64,
+        sender_once: Reply<()>,
+    },
+    TakeCode {
+        code: String,
+        sender_once: Reply<Option<String>>,
+    },
+    Codes {
+        sender_once: Reply<Vec<Code>>,
+    },
+    SetValue {
+        id: String,
+        value: String,
+        sender_once: Reply<()>,
     },
     Get {
         id: String,
-        sender_once: oneshot::Sender<Option<Record>>,
+        sender_once: Reply<Option<Record>>,
     },
     Delete {
         id: String,
+        sender_once: Reply<()>,
+    },
+    CompareAndSet {
+        record: Box<Record>,
+        sender_once: Reply<bool>,
+    },
+    Query {
+        query: Query,
+        cursor: Option<String>,
+        limit: usize,
+        sender_once: Reply<Page>,
+    },
+    Count {
+        query: Query,
+        sender_once: Reply<usize>,
     },
 }
 
-pub struct RedisStore {
-    sender: Sender<Command>,
+// Errors after which the connection can't be used anymore
+fn is_connection_error(error: &SchedulerErrors) -> bool {
+    match error {
+        SchedulerErrors::Redis(error) => {
+            error.is_io_error() || error.is_connection_dropped() || error.is_connection_refusal() || error.is_timeout()
+        }
+        _ => false,
+    }
 }
 
-impl RedisStore {
-    pub async fn new(addr: &str) -> Result<Self, SchedulerErrors> {
-        let mut retry_interval = Duration::from_secs(1);
-        let mut client = redis::Client::open(addr);
+// Connects with exponential backoff, giving up after the given number of attempts
+async fn connect(client: &redis::Client, attempts: usize) -> RedisResult<MultiplexedConnection> {
+    let mut retry_interval = Duration::from_secs(1);
+    let mut attempt = 1;
 
-        for i in 1..6 as usize {
-            if client.is_ok() {
-                break;
+    loop {
+        match client.get_multiplexed_tokio_connection().await {
+            Ok(connection) => return Ok(connection),
+            Err(error) if attempt >= attempts => return Err(error),
+            Err(error) => {
+                info!("Trying to connect to Redis. attempt {}. {}", attempt, error);
+                tokio::time::delay_for(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                attempt += 1;
             }
+        }
+    }
+}
+
+/// The connection shared by all the commands. A multiplexed connection is cheap to clone and
+/// lets commands run concurrently. It's replaced by the supervisor once it breaks
+#[derive(Clone)]
+struct ConnectionHandle {
+    connection: Arc<RwLock<MultiplexedConnection>>,
+    broken: Arc<Notify>,
+    keys: Arc<Keys>,
+}
+
+impl ConnectionHandle {
+    fn current(&self) -> MultiplexedConnection {
+        self.connection.read().clone()
+    }
 
-            info!("Trying to connect to Reddis. attempt {}", i);
-            tokio::time::delay_for(retry_interval).await;
-            retry_interval *= 2;
+    fn reply<T>(&self, sender_once: Reply<T>, result: Result<T, SchedulerErrors>) {
+        if let Err(error) = &result {
+            if is_connection_error(error) {
+                self.broken.notify();
+            }
+        }
 
-            client = redis::Client::open(addr);
+        if sender_once.send(result).is_err() {
+            error!("scheduler.redis_store.reply. receiver was dropped");
         }
+    }
+}
+
+pub struct RedisStore {
+    sender: Sender<Command>,
+    events: Events,
+}
+
+impl RedisStore {
+    pub async fn new(addr: &str, namespace: &str) -> Result<Self, SchedulerErrors> {
+        let client = redis::Client::open(addr)?;
+        let mut connection = connect(&client, CONNECT_ATTEMPTS).await?;
+
+        let keys = Keys::new(namespace);
+        Self::migrate(&mut connection, &keys).await?;
 
-        let client = match client {
-            Ok(client) => client,
-            Err(error) => return Err(error.into()),
+        let handle = ConnectionHandle {
+            connection: Arc::new(RwLock::new(connection)),
+            broken: Arc::new(Notify::new()),
+            keys: Arc::new(keys),
         };
-        let connection = client.get_async_connection().await?;
 
         let (sender, receiver) = mpsc::channel(128);
-        let store = RedisStore { sender };
-        store.launch_receiver(connection, receiver).await;
+        let store = RedisStore {
+            sender,
+            events: Events::default(),
+        };
+        Self::launch_listener(client.clone(), &handle, store.events.clone());
+        Self::launch_supervisor(client, &handle);
+        Self::launch_expiry(&handle);
+        Self::launch_receiver(handle, receiver);
 
         Ok(store)
     }
 
-    async fn launch_receiver(&self, mut connection: Connection, mut receiver: Receiver<Command>) {
+    // Pings the server periodically and reconnects once the connection breaks. The task stops
+    // together with the receiver, when the store is dropped
+    fn launch_supervisor(client: redis::Client, handle: &ConnectionHandle) {
+        let connection = Arc::downgrade(&handle.connection);
+        let broken = handle.broken.clone();
+
         tokio::spawn(async move {
-            while let Some(command) = receiver.recv().await {
-                match command {
-                    Command::Load { sender_once } => match Self::handle_load(&mut connection).await {
-                        Ok(records) => {
-                            if let Err(recs) = sender_once.send(records) {
-                                error!("scheduler.redis_store.Command.Load.send_ok. {:?}", recs);
-                            }
-                        }
-                        Err(error) => {
-                            error!("scheduler.redis_store.Command.Load.handle_load. {}", error);
+            let mut health_check = tokio::time::interval(HEALTH_CHECK_INTERVAL);
 
-                            if sender_once.send(Vec::new()).is_err() {
-                                error!("scheduler.redis_store.Command.Load.send_error");
-                            }
+            loop {
+                tokio::select! {
+                    _ = health_check.tick() => {
+                        let mut current = match connection.upgrade() {
+                            Some(connection) => connection.read().clone(),
+                            None => return,
+                        };
+                        let ping = redis::cmd("PING");
+                        let ping = ping.query_async::<_, String>(&mut current);
+
+                        match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, ping).await {
+                            Ok(Ok(_)) => continue,
+                            Ok(Err(error)) => error!("scheduler.redis_store.health_check. {}", error),
+                            Err(_) => error!("scheduler.redis_store.health_check. timeout"),
                         }
-                    },
-                    Command::Add { record } => {
-                        Self::handle_add(&mut connection, record).await;
                     }
-                    Command::Activate { id, chat_id } => {
-                        Self::handle_activate(&mut connection, id, chat_id).await;
+                    _ = broken.notified() => {}
+                }
+
+                // After the attempts run out, the next health check fails and tries again
+                let reconnected = match connect(&client, CONNECT_ATTEMPTS).await {
+                    Ok(reconnected) => reconnected,
+                    Err(error) => {
+                        error!("scheduler.redis_store.reconnect. {}", error);
+                        continue;
                     }
-                    Command::Get { id, sender_once } => match Self::handle_get(&mut connection, id).await {
-                        Ok(record) => {
-                            if let Err(rec) = sender_once.send(record) {
-                                error!("scheduler.redis_store.Command.Get.ok. {:?}", rec);
-                            }
-                        }
+                };
+
+                match connection.upgrade() {
+                    Some(connection) => *connection.write() = reconnected,
+                    None => return,
+                }
+                info!("Reconnected to Redis");
+            }
+        });
+    }
+
+    // Passes the events of the stream to the listeners of this store. The stream is read with a
+    // connection of its own, since a blocking read holds back all the commands of a shared one
+    fn launch_listener(client: redis::Client, handle: &ConnectionHandle, events: Events) {
+        let alive = Arc::downgrade(&handle.connection);
+        let keys = handle.keys.clone();
+
+        tokio::spawn(async move {
+            let mut last_id = None;
+
+            while alive.upgrade().is_some() {
+                let mut connection = match client.get_async_connection().await {
+                    Ok(connection) => connection,
+                    Err(error) => {
+                        error!("scheduler.redis_store.listen.connect. {}", error);
+                        tokio::time::delay_for(MAX_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+
+                // After a reconnection the reading continues from the last event read
+                let mut current_id = match last_id.take() {
+                    Some(last_id) => last_id,
+                    None => match last_event_id(&mut connection, &keys).await {
+                        Ok(last_id) => last_id,
                         Err(error) => {
-                            error!("scheduler.redis_store.Command.Get.handle_get. {}", error);
+                            error!("scheduler.redis_store.listen.last_event_id. {}", error);
+                            continue;
+                        }
+                    },
+                };
 
-                            if sender_once.send(None).is_err() {
-                                error!("scheduler.redis_store.Command.Get.send_error.");
+                while alive.upgrade().is_some() {
+                    match read_events(&mut connection, &keys, &mut current_id).await {
+                        Ok(read) => {
+                            for event in read {
+                                events.send(event);
                             }
                         }
-                    },
-                    Command::Delete { id } => {
-                        Self::handle_delete(&mut connection, id).await;
+                        Err(error) => {
+                            error!("scheduler.redis_store.listen.read_events. {}", error);
+                            break;
+                        }
                     }
                 }
+
+                last_id = Some(current_id);
             }
         });
     }
 
-    async fn handle_load(connection: &mut Connection) -> Result<Vec<Record>, SchedulerErrors> {
-        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
-        // Delete expired ids and load only the valid ones
-        let ids = match redis::pipe()
-            .cmd("ZREMRANGEBYSCORE")
-            .arg("ids")
-            .arg("-inf")
-            .arg(current_time)
-            .cmd("ZRANGEBYSCORE")
-            .arg("ids")
-            .arg("-inf")
-            .arg("+inf")
-            .query_async::<Connection, Vec<Vec<String>>>(connection)
-            .await
-        {
-            Ok(mut ids) => ids.remove(0),
-            Err(error) => {
-                return Err(SchedulerErrors::Redis(error));
-            }
-        };
+    // Removes the records that expired and reports them. Every store of the namespace runs it, and
+    // TAKE_EXPIRED makes sure each record is handled only once
+    fn launch_expiry(handle: &ConnectionHandle) {
+        let connection = Arc::downgrade(&handle.connection);
+        let keys = handle.keys.clone();
 
-        // Load the records of the valid ids
-        return if !ids.is_empty() {
-            let mut pipeline = redis::pipe();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
 
-            for id in ids {
-                pipeline
-                    .cmd("HMGET")
-                    .arg(id)
-                    .arg("id")
-                    .arg("interval")
-                    .arg("script")
-                    .arg("url")
-                    .arg("chat_id");
-            }
-
-            match pipeline
-                .query_async::<Connection, Vec<Vec<(String, String, String, String, String)>>>(connection)
-                .await
-            {
-                Ok(results) => {
-                    let records: Vec<Record> = results
-                        .into_iter()
-                        .map(|mut result| {
-                            let record = result.remove(0);
-                            Record {
-                                id: record.0,
-                                interval: record.1.parse::<u64>().unwrap(),
-                                script: record.2,
-                                url: record.3,
-                                chat_id: if record.4.is_empty() { None } else { Some(record.4) },
-                            }
-                        })
-                        .collect();
+            loop {
+                interval.tick().await;
+                let mut current = match connection.upgrade() {
+                    Some(connection) => connection.read().clone(),
+                    None => return,
+                };
 
-                    Ok(records)
+                if let Err(error) = Self::handle_expire(&mut current, &keys).await {
+                    error!("scheduler.redis_store.expire. {}", error);
                 }
-                Err(error) => Err(SchedulerErrors::Redis(error)),
             }
-        } else {
-            Ok(Vec::new())
-        };
+        });
     }
 
-    async fn handle_add(connection: &mut Connection, record: Record) {
-        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-
-        let chat_id = if record.chat_id.is_some() {
-            record.chat_id.as_ref().unwrap().as_str()
-        } else {
-            ""
-        };
-
-        if let Err(error) = redis::pipe()
-            .atomic()
-            .cmd("ZADD")
-            .arg("ids")
-            .arg(current_time + MONTH_IN_SECONDS)
-            .arg(&record.id)
-            .cmd("HSET")
-            .arg(&record.id)
-            .arg(&["id", &record.id])
-            .arg(&["url", &record.url])
-            .arg(&["interval", &record.interval.to_string()])
-            .arg(&["script", &record.script])
-            .arg(&["chat_id", chat_id])
-            .cmd("EXPIRE")
-            .arg(&record.id)
-            .arg(MONTH_IN_SECONDS)
-            .query_async::<Connection, ()>(connection)
-            .await
-        {
-            error!("scheduler.redis_store.Command.Add. {}", error);
-        }
+    // Every command runs in its own task so a slow command doesn't hold back the others
+    fn launch_receiver(handle: ConnectionHandle, mut receiver: Receiver<Command>) {
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                let handle = handle.clone();
+                tokio::spawn(async move { Self::execute(&handle, command).await });
+            }
+        });
     }
 
-    async fn handle_activate(connection: &mut Connection, id: String, chat_id: String) {
-        if let Err(error) = redis::pipe()
-            .cmd("HSET")
-            .arg(&id)
-            .arg(&["chat_id", &chat_id])
-            .query_async::<Connection, ()>(connection)
-            .await
-        {
-            error!("scheduler.redis_store.Command.Activate. {}", error);
+    async fn execute(handle: &ConnectionHandle, command: Command) {
+        let connection = &mut handle.current();
+        let keys = &*handle.keys;
+
+        match command {
+            Command::Load { sender_once } => {
+                handle.reply(sender_once, Self::handle_load(connection, keys).await);
+            }
+            Command::Add { record, sender_once } => {
+                handle.reply(sender_once, Self::handle_add(connection, keys, *record).await);
+            }
+            Command::Subscribe {
+                id,
+                chat_id,
+                sender_once,
+            } => {
+                let key = keys.subscribers(&id);
+                let index = Some(keys.chat(&chat_id));
+                handle.reply(
+                    sender_once,
+                    Self::handle_add_member(connection, keys, &id, key, chat_id, index).await,
+                );
+            }
+            Command::Unsubscribe {
+                id,
+                chat_id,
+                sender_once,
+            } => {
+                handle.reply(
+                    sender_once,
+                    Self::handle_unsubscribe(connection, keys, id, chat_id).await,
+                );
+            }
+            Command::Share {
+                id,
+                chat_id,
+                sender_once,
+            } => {
+                let key = keys.shared(&id);
+                handle.reply(
+                    sender_once,
+                    Self::handle_add_member(connection, keys, &id, key, chat_id, None).await,
+                );
+            }
+            Command::SetOwner { id, owner, sender_once } => {
+                handle.reply(sender_once, Self::handle_set_owner(connection, keys, id, owner).await);
+            }
+            Command::Renew {
+                id,
+                expires_at,
+                sender_once,
+            } => {
+                handle.reply(sender_once, Self::handle_renew(connection, keys, id, expires_at).await);
+            }
+            Command::AddCode {
+                code,
+                id,
+                expires_at,
+                sender_once,
+            } => {
+                handle.reply(
+                    sender_once,
+                    Self::handle_add_code(connection, keys, code, id, expires_at).await,
+                );
+            }
+            Command::TakeCode { code, sender_once } => {
+                handle.reply(sender_once, Self::handle_take_code(connection, keys, code).await);
+            }
+            Command::Codes { sender_once } => {
+                handle.reply(sender_once, Self::handle_codes(connection, keys).await);
+            }
+            Command::SetValue { id, value, sender_once } => {
+                handle.reply(sender_once, Self::handle_set_value(connection, keys, id, value).await);
+            }
+            Command::Get { id, sender_once } => {
+                handle.reply(sender_once, Self::handle_get(connection, keys, id).await);
+            }
+            Command::Delete { id, sender_once } => {
+                handle.reply(sender_once, Self::handle_delete(connection, keys, id).await);
+            }
+            Command::CompareAndSet { record, sender_once } => {
+                handle.reply(
+                    sender_once,
+                    Self::handle_compare_and_set(connection, keys, *record).await,
+                );
+            }
+            Command::Query {
+                query,
+                cursor,
+                limit,
+                sender_once,
+            } => {
+                handle.reply(
+                    sender_once,
+                    Self::handle_query(connection, keys, query, cursor, limit).await,
+                );
+            }
+            Command::Count { query, sender_once } => {
+                handle.reply(sender_once, Self::handle_count(connection, keys, query).await);
+            }
         }
     }
 
-    async fn handle_get(connection: &mut Connection, id: String) -> Result<Option<Record>, SchedulerErrors> {
-        // FromRedisValue could be implemented for Record
-        return match redis::pipe()
-            .cmd("HMGET")
-            .arg(&id)
-            .arg("id")
-            .arg("interval")
-            .arg("script")
-            .arg("url")
-            .arg("chat_id")
-            .query_async::<Connection, Vec<Vec<(String, String, String, String, String)>>>(connection)
-            .await
-        {
-            Ok(mut result) => {
-                let result = result.remove(0).remove(0);
-                let record = Record {
-                    id: result.0,
-                    interval: result.1.parse::<u64>().unwrap(),
-                    script: result.2,
-                    url: result.3,
-                    chat_id: if result.4.is_empty() { None } else { Some(result.4) },
-                };
+    // Moves existing data to the current key layout. Data from before namespaces is moved only into
+    // the default namespace, so other deployments sharing the database start empty
+    async fn migrate(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<(), SchedulerErrors> {
+        let version: Option<u64> = redis::cmd("GET").arg(keys.schema()).query_async(connection).await?;
 
-                Ok(Some(record))
+        match version {
+            Some(version) if version > SCHEMA_VERSION => {
+                let error = (
+                    ErrorKind::ClientError,
+                    "Data is stored in a newer key layout",
+                    version.to_string(),
+                );
+                return Err(SchedulerErrors::Redis(error.into()));
             }
-            Err(error) => Err(SchedulerErrors::Redis(error)),
-        };
-    }
+            Some(version) if version == SCHEMA_VERSION => return Ok(()),
+            _ => {}
+        }
 
-    async fn handle_delete(connection: &mut Connection, id: String) {
-        if let Err(error) = redis::pipe()
-            .atomic()
-            .cmd("HDEL")
-            .arg(&id)
-            .arg("id")
-            .arg("url")
-            .arg("interval")
-            .arg("script")
-            .arg("chat_id")
-            .cmd("ZREM")
-            .arg("ids")
-            .arg(&id)
-            .query_async::<Connection, ()>(connection)
-            .await
-        {
-            error!("scheduler.redis_store.Command.delete. {}", error);
+        if version.is_none() && keys.namespace == DEFAULT_NAMESPACE {
+            Self::migrate_unversioned(connection, keys).await?;
         }
-    }
-}
+        Self::migrate_statuses(connection, keys).await?;
 
-#[async_trait]
-impl Store for RedisStore {
-    async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
-        let mut sender = self.sender.clone();
-        let (sender_once, receiver_one) = oneshot::channel();
-        let command = Command::Load { sender_once };
+        redis::cmd("SET")
+            .arg(keys.schema())
+            .arg(SCHEMA_VERSION)
+            .query_async::<_, ()>(connection)
+            .await?;
 
-        sender.send(command).await?;
-        let result = receiver_one.await?;
+        Ok(())
+    }
 
-        let records: HashMap<String, Record> = result.into_iter().map(|record| (record.id.clone(), record)).collect();
+    // Renames the keys of the layout without namespaces. Renaming keeps the expiry, and the members
+    // of the ids set and of the indexes are ids, which don't change. Keys that were already renamed
+    // are skipped, so an interrupted migration continues where it stopped. Records from before the
+    // subscribers sets get theirs afterwards
+    async fn migrate_unversioned(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<(), SchedulerErrors> {
+        // The ids set is renamed last, once it's gone only the subscribers can be left to migrate
+        let renamed: bool = redis::cmd("EXISTS").arg(keys.ids()).query_async(connection).await?;
+        let ids: Vec<String> = redis::cmd("ZRANGE")
+            .arg(if renamed { keys.ids() } else { String::from("ids") })
+            .arg(0)
+            .arg(-1)
+            .query_async(connection)
+            .await?;
 
-        Ok(records)
-    }
+        let mut renames = Vec::new();
+        for id in ids.iter() {
+            renames.push((id.clone(), keys.record(id)));
+            renames.push((format!("{}:subscribers", id), keys.subscribers(id)));
+            renames.push((format!("{}:shared", id), keys.shared(id)));
+        }
+        renames.push((String::from("ids"), keys.ids()));
 
-    async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
-        let mut sender = self.sender.clone();
-        let (sender_once, receiver_once) = oneshot::channel();
-        let command = Command::Get {
-            id: id.into(),
-            sender_once,
-        };
+        // Codes and indexes only gain the namespace in front of their old names
+        for pattern in &["code:*", "chat:*", "owner:*"] {
+            let mut cursor = 0;
+            loop {
+                let (next, found): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(*pattern)
+                    .query_async(connection)
+                    .await?;
 
-        sender.send(command).await?;
-        let result = receiver_once.await?;
+                for old in found {
+                    let new = format!("{}:{}", keys.namespace, old);
+                    renames.push((old, new));
+                }
 
-        Ok(result)
-    }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
 
-    async fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
-        let mut sender = self.sender.clone();
-        let command = Command::Add { record };
-        sender.send(command).await?;
+        if !ids.is_empty() {
+            info!("Migrating {} records to the {} namespace", ids.len(), keys.namespace);
+        }
+        for (old, new) in renames {
+            let exists: bool = redis::cmd("EXISTS").arg(&old).query_async(connection).await?;
+            if exists {
+                redis::cmd("RENAME")
+                    .arg(&old)
+                    .arg(&new)
+                    .query_async::<_, ()>(connection)
+                    .await?;
+            }
+        }
+
+        for id in ids.iter() {
+            Self::migrate_chat_id(connection, keys, id).await?;
+        }
 
         Ok(())
     }
 
-    async fn update(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
-        let mut sender = self.sender.clone();
-        let command = Command::Activate {
-            id: id.into(),
-            chat_id: chat_id.into(),
+    // Before jobs could have several subscribers, the chat that activated a job was kept in the
+    // chat_id field of its hash, empty until then. That chat created the job, so it becomes the owner
+    // as well. The expiry was only kept in the ids set
+    async fn migrate_chat_id(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+    ) -> Result<(), SchedulerErrors> {
+        let record = keys.record(id);
+        let (chat_id, ttl, expires_at): (Option<String>, i64, Option<String>) = redis::pipe()
+            .cmd("HGET")
+            .arg(&record)
+            .arg("chat_id")
+            .cmd("TTL")
+            .arg(&record)
+            .cmd("ZSCORE")
+            .arg(keys.ids())
+            .arg(id)
+            .query_async(connection)
+            .await?;
+
+        let chat_id = match chat_id {
+            Some(chat_id) => chat_id,
+            None => return Ok(()),
         };
-        sender.send(command).await?;
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        if !chat_id.is_empty() {
+            pipeline
+                .cmd("SADD")
+                .arg(keys.subscribers(id))
+                .arg(&chat_id)
+                .ignore()
+                .cmd("SADD")
+                .arg(keys.chat(&chat_id))
+                .arg(id)
+                .ignore()
+                .cmd("SADD")
+                .arg(keys.owner(&chat_id))
+                .arg(id)
+                .ignore()
+                .cmd("HSET")
+                .arg(&record)
+                .arg(&["owner", &chat_id])
+                .arg(&["activated", "1"])
+                .ignore();
+            if ttl >= 0 {
+                pipeline.cmd("EXPIRE").arg(keys.subscribers(id)).arg(ttl).ignore();
+            }
+        }
+        if let Some(expires_at) = expires_at.filter(|expires_at| expires_at.parse::<u64>().is_ok()) {
+            pipeline
+                .cmd("HSETNX")
+                .arg(&record)
+                .arg("expires_at")
+                .arg(expires_at)
+                .ignore();
+        }
+        pipeline.cmd("HDEL").arg(&record).arg("chat_id").ignore();
+
+        pipeline.query_async::<_, ()>(connection).await?;
 
         Ok(())
     }
 
-    async fn delete(&self, id: &str) -> Result<(), SchedulerErrors> {
-        let mut sender = self.sender.clone();
-        let command = Command::Delete { id: id.into() };
-        sender.send(command).await?;
+    // Builds the status indexes of the records stored before they existed
+    async fn migrate_statuses(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<(), SchedulerErrors> {
+        let ids: Vec<String> = redis::cmd("ZRANGE")
+            .arg(keys.ids())
+            .arg(0)
+            .arg(-1)
+            .query_async(connection)
+            .await?;
+
+        for id in ids.iter() {
+            Self::index_status(connection, keys, id).await?;
+        }
+
+        Ok(())
+    }
+
+    // Called after every change that can move a record to another status, and after it's removed
+    async fn index_status(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+    ) -> Result<(), SchedulerErrors> {
+        let script = redis::Script::new(INDEX_STATUS);
+        let mut invocation = script.key(keys.record(id));
+        invocation.key(keys.subscribers(id));
+        for status in STATUSES.iter() {
+            invocation.key(keys.status(*status));
+        }
+
+        invocation.arg(id).invoke_async::<_, ()>(connection).await?;
+
+        Ok(())
+    }
+
+    async fn handle_load(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<Vec<Record>, SchedulerErrors> {
+        // Expired ids are left for handle_expire, which reports them
+        let ids: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(keys.ids())
+            .arg(format!("({}", current_time()))
+            .arg("+inf")
+            .query_async(connection)
+            .await?;
+
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Load the records of the valid ids
+        let mut pipeline = redis::pipe();
+        for id in ids.iter() {
+            query_record(&mut pipeline, keys, id);
+        }
+
+        let replies: Vec<RecordReply> = pipeline.query_async(connection).await?;
+
+        // A single corrupt record shouldn't prevent loading all the others
+        let mut records = Vec::with_capacity(replies.len());
+        for (id, reply) in ids.iter().zip(replies) {
+            match record_from_reply(reply) {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => {}
+                Err(error) => error!("scheduler.redis_store.handle_load. id: {}. {}", id, error),
+            }
+        }
+
+        Ok(records)
+    }
+
+    async fn handle_add(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        record: Record,
+    ) -> Result<(), SchedulerErrors> {
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .cmd("ZADD")
+            .arg(keys.ids())
+            .arg(score(record.expires_at))
+            .arg(&record.id)
+            .cmd("HSET")
+            .arg(keys.record(&record.id))
+            .arg(record.clone());
+        expire(&mut pipeline, &keys.record(&record.id), record.expires_at);
+
+        for (key, members) in &[
+            (keys.subscribers(&record.id), &record.subscribers),
+            (keys.shared(&record.id), &record.shared),
+        ] {
+            if !members.is_empty() {
+                pipeline.cmd("SADD").arg(key).arg(members.iter().collect::<Vec<_>>());
+                expire(&mut pipeline, key, record.expires_at);
+            }
+        }
+
+        for chat_id in record.subscribers.iter() {
+            pipeline.cmd("SADD").arg(keys.chat(chat_id)).arg(&record.id);
+        }
+        if let Some(owner) = &record.owner {
+            pipeline.cmd("SADD").arg(keys.owner(owner)).arg(&record.id);
+        }
+
+        pipeline.query_async::<_, ()>(connection).await?;
+        Self::index_status(connection, keys, &record.id).await?;
+
+        publish(connection, keys, Event::Created { id: record.id }).await
+    }
+
+    // Sets fields of an existing record with UPDATE_FIELDS. None when the record doesn't exist
+    async fn update_fields(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+        fields: &[(&str, &str)],
+    ) -> Result<Option<Vec<String>>, SchedulerErrors> {
+        let script = redis::Script::new(UPDATE_FIELDS);
+        let mut invocation = script.key(keys.record(id));
+        for (field, value) in fields {
+            invocation.arg(*field).arg(*value);
+        }
+
+        Ok(invocation.invoke_async(connection).await?)
+    }
+
+    // Adds a member to one of the sets kept next to the record's hash, and the id to the index of
+    // the member if there's one
+    async fn handle_add_member(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+        key: String,
+        member: String,
+        index: Option<String>,
+    ) -> Result<(), SchedulerErrors> {
+        // The set has to expire together with the record itself
+        let ttl = redis::cmd("TTL")
+            .arg(keys.record(id))
+            .query_async::<_, i64>(connection)
+            .await?;
+
+        // -2 means that the key doesn't exist, -1 that it never expires
+        if ttl == -2 {
+            return Err(SchedulerErrors::NotFound(id.to_string()));
+        }
+
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .cmd("SADD")
+            .arg(&key)
+            .arg(&member)
+            .cmd("SCARD")
+            .arg(&key);
+        if ttl >= 0 {
+            pipeline.cmd("EXPIRE").arg(&key).arg(ttl).ignore();
+        }
+        if let Some(index) = index {
+            pipeline.cmd("SADD").arg(index).arg(id).ignore();
+        }
+
+        let (added, members): (usize, usize) = pipeline.query_async(connection).await?;
+        if added == 0 {
+            return Ok(());
+        }
+        Self::update_fields(connection, keys, id, &[]).await?;
+        Self::index_status(connection, keys, id).await?;
+
+        let id = id.to_string();
+        let event = if members == 1 && key == keys.subscribers(&id) {
+            Event::Activated { id }
+        } else {
+            Event::Updated { id }
+        };
+
+        publish(connection, keys, event).await
+    }
+
+    async fn handle_renew(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+        expires_at: Option<u64>,
+    ) -> Result<(), SchedulerErrors> {
+        let fields = [("expires_at", optional_to_string(expires_at))];
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(field, value)| (*field, value.as_str())).collect();
+        if Self::update_fields(connection, keys, &id, &fields).await?.is_none() {
+            return Err(SchedulerErrors::NotFound(id));
+        }
+
+        Self::set_expiry(connection, keys, &id, expires_at).await?;
+        publish(connection, keys, Event::Updated { id }).await
+    }
+
+    // Moves the expiry of all the keys of a record
+    async fn set_expiry(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+        expires_at: Option<u64>,
+    ) -> Result<(), SchedulerErrors> {
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .cmd("ZADD")
+            .arg(keys.ids())
+            .arg("XX")
+            .arg(score(expires_at))
+            .arg(id);
+
+        for key in &[keys.record(id), keys.subscribers(id), keys.shared(id)] {
+            expire(&mut pipeline, key, expires_at);
+        }
+
+        pipeline.query_async::<_, ()>(connection).await?;
 
         Ok(())
     }
+
+    async fn handle_add_code(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        code: String,
+        id: String,
+        expires_at: u64,
+    ) -> Result<(), SchedulerErrors> {
+        let key = keys.code(&code);
+        redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg(&id)
+            .cmd("EXPIREAT")
+            .arg(&key)
+            .arg(expires_at)
+            .query_async::<_, ()>(connection)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_take_code(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        code: String,
+    ) -> Result<Option<String>, SchedulerErrors> {
+        let key = keys.code(&code);
+        let (id, _): (Option<String>, i64) = redis::pipe()
+            .atomic()
+            .cmd("GET")
+            .arg(&key)
+            .cmd("DEL")
+            .arg(&key)
+            .query_async(connection)
+            .await?;
+
+        Ok(id)
+    }
+
+    // Codes are kept in keys of their own that expire together with them
+    async fn handle_codes(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<Vec<Code>, SchedulerErrors> {
+        let prefix = keys.code("");
+        let mut found = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(format!("{}*", prefix))
+                .arg("COUNT")
+                .arg(QUERY_BATCH)
+                .query_async(connection)
+                .await?;
+            found.extend(batch);
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        let now = current_time();
+        let mut codes = Vec::new();
+        for batch in found.chunks(QUERY_BATCH) {
+            let mut pipeline = redis::pipe();
+            for key in batch.iter() {
+                pipeline.cmd("GET").arg(key).cmd("TTL").arg(key);
+            }
+
+            let replies: Vec<(Option<String>, i64)> = pipeline.query_async(connection).await?;
+            for (key, (id, ttl)) in batch.iter().zip(replies) {
+                // Taken or expired since the scan
+                if let (Some(id), true) = (id, ttl > 0) {
+                    codes.push(Code {
+                        code: key[prefix.len()..].to_string(),
+                        id,
+                        expires_at: now + ttl as u64,
+                    });
+                }
+            }
+        }
+
+        Ok(codes)
+    }
+
+    async fn handle_set_owner(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+        owner: String,
+    ) -> Result<(), SchedulerErrors> {
+        let previous = match Self::update_fields(connection, keys, &id, &[("owner", &owner)]).await? {
+            Some(previous) => previous.into_iter().next().unwrap_or_default(),
+            None => return Err(SchedulerErrors::NotFound(id)),
+        };
+
+        Self::move_owner(connection, keys, &id, &previous, &owner).await?;
+        publish(connection, keys, Event::Updated { id }).await
+    }
+
+    // Moves the id between the owner indexes
+    async fn move_owner(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: &str,
+        previous: &str,
+        owner: &str,
+    ) -> Result<(), SchedulerErrors> {
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        if !previous.is_empty() && previous != owner {
+            pipeline.cmd("SREM").arg(keys.owner(previous)).arg(id);
+        }
+        if !owner.is_empty() {
+            pipeline.cmd("SADD").arg(keys.owner(owner)).arg(id);
+        }
+
+        pipeline.query_async::<_, ()>(connection).await?;
+
+        Ok(())
+    }
+
+    async fn handle_unsubscribe(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+        chat_id: String,
+    ) -> Result<(), SchedulerErrors> {
+        let (removed, _): (i64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(keys.subscribers(&id))
+            .arg(&chat_id)
+            .cmd("SREM")
+            .arg(keys.chat(&chat_id))
+            .arg(&id)
+            .query_async(connection)
+            .await?;
+
+        if removed == 0 {
+            return Ok(());
+        }
+
+        Self::update_fields(connection, keys, &id, &[]).await?;
+        Self::index_status(connection, keys, &id).await?;
+        publish(connection, keys, Event::Updated { id }).await
+    }
+
+    async fn handle_set_value(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+        value: String,
+    ) -> Result<(), SchedulerErrors> {
+        match Self::update_fields(connection, keys, &id, &[("last_value", &value)]).await? {
+            Some(_) => publish(connection, keys, Event::Updated { id }).await,
+            None => Err(SchedulerErrors::NotFound(id)),
+        }
+    }
+
+    async fn handle_get(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+    ) -> Result<Option<Record>, SchedulerErrors> {
+        let mut pipeline = redis::pipe();
+        query_record(&mut pipeline, keys, &id);
+
+        let reply: RecordReply = pipeline.query_async(connection).await?;
+        let record = record_from_reply(reply)?;
+
+        // Expired records are kept until handle_expire removes them
+        Ok(record.filter(|record| !record.is_expired()))
+    }
+
+    async fn handle_delete(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        id: String,
+    ) -> Result<(), SchedulerErrors> {
+        if Self::remove(connection, keys, &id).await? {
+            publish(connection, keys, Event::Deleted { id }).await?;
+        }
+
+        Ok(())
+    }
+
+    // Reports the records taken by TAKE_EXPIRED and removes them
+    async fn handle_expire(connection: &mut MultiplexedConnection, keys: &Keys) -> Result<(), SchedulerErrors> {
+        loop {
+            let ids: Vec<String> = redis::Script::new(TAKE_EXPIRED)
+                .key(keys.ids())
+                .arg(current_time())
+                .arg(QUERY_BATCH)
+                .invoke_async(connection)
+                .await?;
+
+            for id in ids.iter() {
+                let mut pipeline = redis::pipe();
+                query_record(&mut pipeline, keys, id);
+                let reply: RecordReply = pipeline.query_async(connection).await?;
+
+                match record_from_reply(reply) {
+                    // Renewed after it was taken
+                    Ok(Some(record)) if !record.is_expired() => {
+                        redis::cmd("ZADD")
+                            .arg(keys.ids())
+                            .arg(score(record.expires_at))
+                            .arg(id)
+                            .query_async::<_, ()>(connection)
+                            .await?;
+                    }
+                    Ok(Some(record)) => {
+                        Self::remove(connection, keys, id).await?;
+                        publish(connection, keys, Event::Expired(Box::new(record))).await?;
+                    }
+                    // Nothing left to report once the keys themselves expired
+                    Ok(None) => {
+                        Self::remove(connection, keys, id).await?;
+                    }
+                    Err(error) => {
+                        error!("scheduler.redis_store.handle_expire. id: {}. {}", id, error);
+                        Self::remove(connection, keys, id).await?;
+                    }
+                }
+            }
+
+            if ids.len() < QUERY_BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    // Deletes the keys of a record and its entries in the indexes. Returns whether it existed
+    async fn remove(connection: &mut MultiplexedConnection, keys: &Keys, id: &str) -> Result<bool, SchedulerErrors> {
+        // The indexes point to the record, so they're cleaned up together with it
+        let (owner, subscribers): (Option<String>, Vec<String>) = redis::pipe()
+            .cmd("HGET")
+            .arg(keys.record(id))
+            .arg("owner")
+            .cmd("SMEMBERS")
+            .arg(keys.subscribers(id))
+            .query_async(connection)
+            .await?;
+
+        let mut pipeline = redis::pipe();
+        pipeline
+            .atomic()
+            .cmd("DEL")
+            .arg(keys.record(id))
+            .cmd("DEL")
+            .arg(keys.subscribers(id))
+            .arg(keys.shared(id))
+            .ignore()
+            .cmd("ZREM")
+            .arg(keys.ids())
+            .arg(id)
+            .ignore();
+        for status in STATUSES.iter() {
+            pipeline.cmd("ZREM").arg(keys.status(*status)).arg(id).ignore();
+        }
+
+        for chat_id in subscribers.iter() {
+            pipeline.cmd("SREM").arg(keys.chat(chat_id)).arg(id).ignore();
+        }
+        if let Some(owner) = owner.filter(|owner| !owner.is_empty()) {
+            pipeline.cmd("SREM").arg(keys.owner(&owner)).arg(id).ignore();
+        }
+
+        let (deleted,): (usize,) = pipeline.query_async(connection).await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn handle_compare_and_set(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        record: Record,
+    ) -> Result<bool, SchedulerErrors> {
+        let (status, previous): (i64, String) = redis::Script::new(COMPARE_AND_SET)
+            .key(keys.record(&record.id))
+            .arg(record.version)
+            .arg(record.clone())
+            .invoke_async(connection)
+            .await?;
+
+        match status {
+            -1 => return Err(SchedulerErrors::NotFound(record.id)),
+            0 => return Ok(false),
+            _ => {}
+        }
+
+        let owner = record.owner.as_deref().unwrap_or_default();
+        Self::move_owner(connection, keys, &record.id, &previous, owner).await?;
+        Self::set_expiry(connection, keys, &record.id, record.expires_at).await?;
+        Self::index_status(connection, keys, &record.id).await?;
+        publish(connection, keys, Event::Updated { id: record.id }).await?;
+
+        Ok(true)
+    }
+
+    // Looks up the candidates in the indexes and filters them by the rest of the query. Index
+    // entries of records that expired or no longer match are removed along the way
+    async fn handle_query(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        query: Query,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<Page, SchedulerErrors> {
+        let indexes: Vec<String> = query
+            .chat_id
+            .iter()
+            .map(|chat_id| keys.chat(chat_id))
+            .chain(query.owner.iter().map(|owner| keys.owner(owner)))
+            .collect();
+        // Every record is in one of the status indexes, which are ordered by id
+        if indexes.is_empty() {
+            let statuses = match query.status {
+                Some(status) => vec![status],
+                None => STATUSES.to_vec(),
+            };
+            return Self::query_statuses(connection, keys, &statuses, cursor, limit).await;
+        }
+
+        let mut ids: Vec<String> = redis::cmd("SINTER").arg(&indexes[..]).query_async(connection).await?;
+
+        ids.sort();
+        if let Some(cursor) = &cursor {
+            ids.retain(|id| id > cursor);
+        }
+
+        let mut records = Vec::new();
+        let mut stale = Vec::new();
+        for batch in ids.chunks(QUERY_BATCH) {
+            let mut pipeline = redis::pipe();
+            for id in batch.iter() {
+                query_record(&mut pipeline, keys, id);
+            }
+
+            let replies: Vec<RecordReply> = pipeline.query_async(connection).await?;
+            for (id, reply) in batch.iter().zip(replies) {
+                match record_from_reply(reply) {
+                    // Left for handle_expire, which cleans up the indexes too
+                    Ok(Some(record)) if record.is_expired() => {}
+                    Ok(Some(record)) if query.matches(&record) => records.push(record),
+                    Ok(Some(record)) => {
+                        let matches_indexes = Query {
+                            status: None,
+                            ..query.clone()
+                        };
+                        if !matches_indexes.matches(&record) {
+                            stale.push(id.clone());
+                        }
+                    }
+                    Ok(None) => stale.push(id.clone()),
+                    Err(error) => error!("scheduler.redis_store.handle_query. id: {}. {}", id, error),
+                }
+            }
+
+            if records.len() > limit {
+                break;
+            }
+        }
+
+        if !stale.is_empty() && !indexes.is_empty() {
+            let mut pipeline = redis::pipe();
+            for index in indexes.iter() {
+                pipeline.cmd("SREM").arg(index).arg(&stale[..]);
+            }
+            pipeline.query_async::<_, ()>(connection).await?;
+        }
+
+        Ok(Page::from_sorted(records, limit))
+    }
+
+    // Pages through the indexes of the statuses together in order of id, reading only the records
+    // the page needs
+    async fn query_statuses(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        statuses: &[Status],
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<Page, SchedulerErrors> {
+        let indexes: Vec<String> = statuses.iter().map(|status| keys.status(*status)).collect();
+        let mut start = match cursor {
+            Some(cursor) => format!("({}", cursor),
+            None => String::from("-"),
+        };
+
+        let mut records = Vec::new();
+        let mut stale = Vec::new();
+        while records.len() <= limit {
+            let mut pipeline = redis::pipe();
+            for index in indexes.iter() {
+                pipeline
+                    .cmd("ZRANGEBYLEX")
+                    .arg(index)
+                    .arg(&start)
+                    .arg("+")
+                    .arg("LIMIT")
+                    .arg(0)
+                    .arg(QUERY_BATCH);
+            }
+            let batches: Vec<Vec<String>> = pipeline.query_async(connection).await?;
+
+            // Every id up to the end of the shortest full batch was read from all the indexes
+            let end = batches
+                .iter()
+                .filter(|batch| batch.len() == QUERY_BATCH)
+                .filter_map(|batch| batch.last())
+                .min()
+                .cloned();
+            let mut ids: Vec<String> = batches
+                .into_iter()
+                .flatten()
+                .filter(|id| !matches!(&end, Some(end) if id > end))
+                .collect();
+            ids.sort();
+            ids.dedup();
+            if ids.is_empty() {
+                break;
+            }
+
+            let mut pipeline = redis::pipe();
+            for id in ids.iter() {
+                query_record(&mut pipeline, keys, id);
+            }
+
+            let replies: Vec<RecordReply> = pipeline.query_async(connection).await?;
+            for (id, reply) in ids.iter().zip(replies) {
+                match record_from_reply(reply) {
+                    // Left for handle_expire, which cleans up the indexes too
+                    Ok(Some(record)) if record.is_expired() => {}
+                    // Changed after the ids were read. the change moves it to its index
+                    Ok(Some(record)) if !statuses.contains(&record.status()) => {}
+                    Ok(Some(record)) => records.push(record),
+                    Ok(None) => stale.push(id.clone()),
+                    Err(error) => error!("scheduler.redis_store.query_statuses. id: {}. {}", id, error),
+                }
+            }
+
+            match end {
+                Some(end) => start = format!("({}", end),
+                None => break,
+            }
+        }
+
+        if !stale.is_empty() {
+            let mut pipeline = redis::pipe();
+            for index in indexes.iter() {
+                pipeline.cmd("ZREM").arg(index).arg(&stale[..]).ignore();
+            }
+            pipeline.query_async::<_, ()>(connection).await?;
+        }
+
+        Ok(Page::from_sorted(records, limit))
+    }
+
+    // Queries with a single filter are counted by the size of its index. records that expired are
+    // counted until handle_expire removes them, which it does every EXPIRY_CHECK_INTERVAL
+    async fn handle_count(
+        connection: &mut MultiplexedConnection,
+        keys: &Keys,
+        query: Query,
+    ) -> Result<usize, SchedulerErrors> {
+        let (command, key) = match (&query.chat_id, &query.owner, query.status) {
+            (None, None, None) => ("ZCARD", keys.ids()),
+            (None, None, Some(status)) => ("ZCARD", keys.status(status)),
+            (Some(chat_id), None, None) => ("SCARD", keys.chat(chat_id)),
+            (None, Some(owner), None) => ("SCARD", keys.owner(owner)),
+            _ => {
+                let page = Self::handle_query(connection, keys, query, None, usize::MAX).await?;
+                return Ok(page.records.len());
+            }
+        };
+
+        Ok(redis::cmd(command).arg(key).query_async(connection).await?)
+    }
+
+    // Sends a command to the receiver task and waits for its reply
+    async fn send<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, SchedulerErrors> {
+        let mut sender = self.sender.clone();
+        let (sender_once, receiver_once) = oneshot::channel();
+
+        sender.send(command(sender_once)).await?;
+        receiver_once.await?
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
+        let result = self.send(|sender_once| Command::Load { sender_once }).await?;
+
+        let records: HashMap<String, Record> = result.into_iter().map(|record| (record.id.clone(), record)).collect();
+
+        Ok(records)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
+        self.send(|sender_once| Command::Get {
+            id: id.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Add {
+            record: Box::new(record),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn subscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Subscribe {
+            id: id.into(),
+            chat_id: chat_id.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn unsubscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Unsubscribe {
+            id: id.into(),
+            chat_id: chat_id.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn share(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Share {
+            id: id.into(),
+            chat_id: chat_id.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn set_owner(&self, id: &str, owner: &str) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::SetOwner {
+            id: id.into(),
+            owner: owner.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn renew(&self, id: &str, expires_at: Option<u64>) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Renew {
+            id: id.into(),
+            expires_at,
+            sender_once,
+        })
+        .await
+    }
+
+    async fn add_code(&self, code: &str, id: &str, expires_at: u64) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::AddCode {
+            code: code.into(),
+            id: id.into(),
+            expires_at,
+            sender_once,
+        })
+        .await
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<String>, SchedulerErrors> {
+        self.send(|sender_once| Command::TakeCode {
+            code: code.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn codes(&self) -> Result<Vec<Code>, SchedulerErrors> {
+        self.send(|sender_once| Command::Codes { sender_once }).await
+    }
+
+    async fn set_value(&self, id: &str, value: &serde_json::Value) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::SetValue {
+            id: id.into(),
+            value: value.to_string(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), SchedulerErrors> {
+        self.send(|sender_once| Command::Delete {
+            id: id.into(),
+            sender_once,
+        })
+        .await
+    }
+
+    async fn compare_and_set(&self, record: Record) -> Result<bool, SchedulerErrors> {
+        self.send(|sender_once| Command::CompareAndSet {
+            record: Box::new(record),
+            sender_once,
+        })
+        .await
+    }
+
+    fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    async fn query(&self, query: &Query, cursor: Option<&str>, limit: usize) -> Result<Page, SchedulerErrors> {
+        self.send(|sender_once| Command::Query {
+            query: query.clone(),
+            cursor: cursor.map(String::from),
+            limit,
+            sender_once,
+        })
+        .await
+    }
+
+    async fn count(&self, query: &Query) -> Result<usize, SchedulerErrors> {
+        self.send(|sender_once| Command::Count {
+            query: query.clone(),
+            sender_once,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::record;
+    use serde_json::json;
+
+    // The reply HGETALL gives for the fields
+    fn hash(fields: &[(&str, &str)]) -> Value {
+        let values = fields
+            .iter()
+            .flat_map(|(field, value)| {
+                vec![
+                    Value::Data(field.as_bytes().to_vec()),
+                    Value::Data(value.as_bytes().to_vec()),
+                ]
+            })
+            .collect();
+
+        Value::Bulk(values)
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut stored = record("a");
+        stored.last_value = Some(json!({"price": 10}));
+        stored.ttl = Some(3_600);
+        stored.expires_at = Some(1_000);
+        stored.version = 3;
+        stored.activated = Some(false);
+
+        let values = stored.to_redis_args().into_iter().map(Value::Data).collect();
+        let read = Record::from_redis_value(&Value::Bulk(values)).unwrap();
+
+        assert_eq!(read.id, stored.id);
+        assert_eq!(read.url, stored.url);
+        assert_eq!(read.last_value, stored.last_value);
+        assert_eq!(read.owner, stored.owner);
+        assert_eq!(read.ttl, stored.ttl);
+        assert_eq!(read.expires_at, stored.expires_at);
+        assert_eq!(read.version, 3);
+        assert_eq!(read.activated, Some(false));
+    }
+
+    #[test]
+    fn legacy_record() {
+        let fields = [
+            ("id", "a"),
+            ("url", "https://example.com"),
+            ("interval", "5"),
+            ("script", ""),
+            ("owner", ""),
+            ("ttl", ""),
+            ("expires_at", ""),
+        ];
+        let read = Record::from_redis_value(&hash(&fields)).unwrap();
+
+        assert_eq!(read.owner, None);
+        assert_eq!(read.ttl, None);
+        assert_eq!(read.version, 0);
+        assert_eq!(read.activated, None);
+        assert_eq!(read.status(), Status::Pending);
+    }
+
+    #[test]
+    fn invalid_record() {
+        let fields = [("id", "a"), ("url", ""), ("interval", "often"), ("script", "")];
+        assert!(Record::from_redis_value(&hash(&fields)).is_err());
+
+        let fields = [("id", "a"), ("url", ""), ("interval", "5")];
+        assert!(Record::from_redis_value(&hash(&fields)).is_err());
+    }
+
+    // Runs against the Redis at REDIS_TEST_URL and is skipped when it isn't set. The layout without
+    // namespaces has no prefix of its own, so the database has to be empty, and it's flushed afterwards
+    #[tokio::test]
+    async fn migrates_baseline_layout() {
+        let url = match std::env::var("REDIS_TEST_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let client = redis::Client::open(url.as_str()).unwrap();
+        let mut connection = client.get_multiplexed_tokio_connection().await.unwrap();
+        let size: usize = redis::cmd("DBSIZE").query_async(&mut connection).await.unwrap();
+        assert_eq!(size, 0, "REDIS_TEST_URL has to point to an empty database");
+
+        // What the first versions stored, a job activated from chat 1 and one that's still pending
+        let expires_at = current_time() + 3_600;
+        let mut pipeline = redis::pipe();
+        for (id, chat_id) in &[("a", "1"), ("b", "")] {
+            pipeline
+                .cmd("ZADD")
+                .arg("ids")
+                .arg(expires_at)
+                .arg(*id)
+                .cmd("HSET")
+                .arg(*id)
+                .arg(&["id", *id])
+                .arg(&["url", "https://example.com"])
+                .arg(&["interval", "5"])
+                .arg(&["script", "return true"])
+                .arg(&["chat_id", *chat_id])
+                .cmd("EXPIRE")
+                .arg(*id)
+                .arg(3_600);
+        }
+        pipeline.query_async::<_, ()>(&mut connection).await.unwrap();
+
+        let keys = Keys::new(DEFAULT_NAMESPACE);
+        RedisStore::migrate(&mut connection, &keys).await.unwrap();
+
+        let active = RedisStore::handle_get(&mut connection, &keys, String::from("a"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(active.status(), Status::Active);
+        assert!(active.subscribers.contains("1"));
+        assert_eq!(active.owner.as_deref(), Some("1"));
+        assert_eq!(active.expires_at, Some(expires_at));
+
+        let pending = RedisStore::handle_get(&mut connection, &keys, String::from("b"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.status(), Status::Pending);
+        assert!(pending.subscribers.is_empty());
+
+        let chat = Query {
+            chat_id: Some(String::from("1")),
+            ..Query::default()
+        };
+        let page = RedisStore::handle_query(&mut connection, &keys, chat, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(page.records.len(), 1);
+        let (chat_id,): (Option<String>,) = redis::pipe()
+            .cmd("HGET")
+            .arg(keys.record("a"))
+            .arg("chat_id")
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(chat_id, None);
+
+        redis::cmd("FLUSHDB")
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn keys_in_namespace() {
+        let keys = Keys::new("test");
+
+        assert_eq!(keys.record("a"), "test:record:a");
+        assert_eq!(keys.subscribers("a"), "test:record:a:subscribers");
+        assert_eq!(keys.status(Status::Paused), "test:status:paused");
+        assert_eq!(keys.code("123"), "test:code:123");
+        assert_eq!(score(None), "+inf");
+        assert_eq!(score(Some(10)), "10");
+    }
 }
@@ -0,0 +1,796 @@
+use crate::store::{current_time, Code, Event, Events, Page, Query, Record, Status, Store, EXPIRY_CHECK_INTERVAL};
+use crate::SchedulerErrors;
+use async_trait::async_trait;
+use log::error;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row, ToSql, Transaction, NO_PARAMS};
+use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use tokio::sync::broadcast;
+
+// The statements stick to SQL that Postgres accepts too
+const INITIAL_SCHEMA: &str = "CREATE TABLE jobs (
+        id TEXT PRIMARY KEY,
+        url TEXT NOT NULL,
+        interval BIGINT NOT NULL,
+        script TEXT NOT NULL,
+        last_value TEXT,
+        owner TEXT,
+        ttl BIGINT,
+        expires_at BIGINT
+    );
+    CREATE INDEX jobs_expires_at ON jobs (expires_at);
+    CREATE INDEX jobs_owner ON jobs (owner);
+
+    CREATE TABLE subscribers (
+        job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+        chat_id TEXT NOT NULL,
+        PRIMARY KEY (job_id, chat_id)
+    );
+    CREATE INDEX subscribers_chat_id ON subscribers (chat_id);
+
+    CREATE TABLE shared (
+        job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+        chat_id TEXT NOT NULL,
+        PRIMARY KEY (job_id, chat_id)
+    );
+    CREATE INDEX shared_chat_id ON shared (chat_id);
+
+    CREATE TABLE codes (
+        code TEXT PRIMARY KEY,
+        job_id TEXT NOT NULL,
+        expires_at BIGINT NOT NULL
+    );";
+
+/// Schema changes, applied in order. The version of a migration is its position in the list, and
+/// an applied migration must never change
+const MIGRATIONS: &[&str] = &[
+    INITIAL_SCHEMA,
+    "ALTER TABLE jobs ADD COLUMN version BIGINT NOT NULL DEFAULT 0",
+    // Jobs were activated once they had an owner, before the flag was stored
+    "ALTER TABLE jobs ADD COLUMN activated BOOLEAN NOT NULL DEFAULT FALSE;
+    UPDATE jobs SET activated = owner IS NOT NULL;
+    CREATE INDEX jobs_activated ON jobs (activated);",
+];
+
+const JOB_COLUMNS: &str = "id, url, interval, script, last_value, owner, ttl, expires_at, version, activated";
+
+fn migrate(connection: &mut Connection) -> Result<(), SchedulerErrors> {
+    connection.execute_batch("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY)")?;
+
+    let applied: i64 = connection.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version) VALUES (?1)",
+            params![index as i64 + 1],
+        )?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+// SQLite stores integers as i64
+fn to_sql(value: Option<u64>) -> Option<i64> {
+    value.map(|value| value as i64)
+}
+
+fn record_from_row(row: &Row) -> rusqlite::Result<Record> {
+    let last_value: Option<String> = row.get(4)?;
+    let ttl: Option<i64> = row.get(6)?;
+    let expires_at: Option<i64> = row.get(7)?;
+    let version: i64 = row.get(8)?;
+
+    Ok(Record {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        interval: row.get::<_, i64>(2)? as u64,
+        script: row.get(3)?,
+        subscribers: HashSet::new(),
+        last_value: last_value.and_then(|value| serde_json::from_str(&value).ok()),
+        owner: row.get(5)?,
+        shared: HashSet::new(),
+        ttl: ttl.map(|ttl| ttl as u64),
+        expires_at: expires_at.map(|expires_at| expires_at as u64),
+        version: version as u64,
+        activated: Some(row.get(9)?),
+    })
+}
+
+// Chat ids of a job in either the subscribers or the shared table
+fn members(connection: &Connection, table: &str, id: &str) -> rusqlite::Result<HashSet<String>> {
+    let mut statement = connection.prepare(&format!("SELECT chat_id FROM {} WHERE job_id = ?1", table))?;
+    let rows = statement.query_map(params![id], |row| row.get(0))?;
+
+    rows.collect()
+}
+
+fn get(connection: &Connection, id: &str) -> Result<Option<Record>, SchedulerErrors> {
+    let query = format!(
+        "SELECT {} FROM jobs WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+        JOB_COLUMNS
+    );
+    let record = connection
+        .query_row(&query, params![id, current_time() as i64], record_from_row)
+        .optional()?;
+
+    let mut record = match record {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    record.subscribers = members(connection, "subscribers", id)?;
+    record.shared = members(connection, "shared", id)?;
+
+    Ok(Some(record))
+}
+
+// WHERE clause and parameters of a query. expired jobs are always left out
+fn filter(query: &Query, cursor: Option<&str>) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut conditions = vec![String::from("(expires_at IS NULL OR expires_at > ?)")];
+    let mut parameters: Vec<Box<dyn ToSql>> = vec![Box::new(current_time() as i64)];
+
+    if let Some(chat_id) = &query.chat_id {
+        conditions.push(String::from(
+            "EXISTS (SELECT 1 FROM subscribers WHERE job_id = jobs.id AND chat_id = ?)",
+        ));
+        parameters.push(Box::new(chat_id.clone()));
+    }
+    if let Some(owner) = &query.owner {
+        conditions.push(String::from("owner = ?"));
+        parameters.push(Box::new(owner.clone()));
+    }
+    if let Some(status) = query.status {
+        let condition = match status {
+            Status::Pending => "NOT activated",
+            Status::Active => "activated AND EXISTS (SELECT 1 FROM subscribers WHERE job_id = jobs.id)",
+            Status::Paused => "activated AND NOT EXISTS (SELECT 1 FROM subscribers WHERE job_id = jobs.id)",
+        };
+        conditions.push(String::from(condition));
+    }
+    if let Some(cursor) = cursor {
+        conditions.push(String::from("id > ?"));
+        parameters.push(Box::new(cursor.to_string()));
+    }
+
+    (conditions.join(" AND "), parameters)
+}
+
+// Replaces the chat ids of a job in the subscribers and shared tables
+fn set_members(transaction: &Transaction, record: &Record) -> rusqlite::Result<()> {
+    for (table, members) in &[("subscribers", &record.subscribers), ("shared", &record.shared)] {
+        transaction.execute(&format!("DELETE FROM {} WHERE job_id = ?1", table), params![record.id])?;
+        for chat_id in members.iter() {
+            transaction.execute(
+                &format!("INSERT INTO {} (job_id, chat_id) VALUES (?1, ?2)", table),
+                params![record.id, chat_id],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn bump_version(connection: &Connection, id: &str) -> rusqlite::Result<usize> {
+    connection.execute("UPDATE jobs SET version = version + 1 WHERE id = ?1", params![id])
+}
+
+// Deletes a job together with its chat ids and codes. Returns the number of deleted jobs
+fn remove(connection: &Connection, id: &str) -> rusqlite::Result<usize> {
+    for table in &["subscribers", "shared"] {
+        connection.execute(&format!("DELETE FROM {} WHERE job_id = ?1", table), params![id])?;
+    }
+    connection.execute("DELETE FROM codes WHERE job_id = ?1", params![id])?;
+
+    connection.execute("DELETE FROM jobs WHERE id = ?1", params![id])
+}
+
+// Deletes the expired jobs and codes. Returns the jobs as they were before they were deleted
+fn expire(connection: &Connection) -> Result<Vec<Record>, SchedulerErrors> {
+    let now = current_time() as i64;
+    let mut records = {
+        let query = format!(
+            "SELECT {} FROM jobs WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            JOB_COLUMNS
+        );
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement.query_map(params![now], record_from_row)?;
+        rows.collect::<rusqlite::Result<Vec<Record>>>()?
+    };
+
+    for record in records.iter_mut() {
+        record.subscribers = members(connection, "subscribers", &record.id)?;
+        record.shared = members(connection, "shared", &record.id)?;
+        remove(connection, &record.id)?;
+    }
+    connection.execute("DELETE FROM codes WHERE expires_at <= ?1", params![now])?;
+
+    Ok(records)
+}
+
+fn exists(connection: &Connection, id: &str) -> Result<(), SchedulerErrors> {
+    let found = connection
+        .query_row("SELECT 1 FROM jobs WHERE id = ?1", params![id], |_| Ok(()))
+        .optional()?;
+
+    found.ok_or_else(|| SchedulerErrors::NotFound(id.to_string()))
+}
+
+/// Store backed by a relational database, for deployments that want to query the jobs with SQL.
+/// It uses an embedded SQLite database, and the schema is kept portable to Postgres
+pub struct SqlStore {
+    connection: Arc<Mutex<Connection>>,
+    events: Events,
+}
+
+impl SqlStore {
+    pub async fn new<P>(path: P) -> Result<Self, SchedulerErrors>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let connection = tokio::task::spawn_blocking(move || -> Result<Connection, SchedulerErrors> {
+            let mut connection = Connection::open(path)?;
+            connection.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+            migrate(&mut connection)?;
+
+            Ok(connection)
+        })
+        .await??;
+
+        let store = Self {
+            connection: Arc::new(Mutex::new(connection)),
+            events: Events::default(),
+        };
+        store.launch_expiry();
+
+        Ok(store)
+    }
+
+    // Deletes expired jobs as soon as they expire, so they're reported right away. The task stops
+    // once the store is dropped
+    fn launch_expiry(&self) {
+        let connection = Arc::downgrade(&self.connection);
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                let connection = match connection.upgrade() {
+                    Some(connection) => connection,
+                    None => return,
+                };
+
+                let result = tokio::task::spawn_blocking(move || {
+                    let mut connection = connection.lock();
+                    let transaction = connection.transaction()?;
+                    let expired = expire(&transaction)?;
+                    transaction.commit()?;
+
+                    Ok(expired)
+                })
+                .await;
+
+                match result.map_err(SchedulerErrors::from).and_then(|result| result) {
+                    Ok(expired) => {
+                        for record in expired {
+                            events.send(Event::Expired(Box::new(record)));
+                        }
+                    }
+                    Err(error) => error!("scheduler.sql_store.expire. {}", error),
+                }
+            }
+        });
+    }
+
+    // The driver blocks, so queries run outside of the async runtime
+    async fn run<T, F>(&self, operation: F) -> Result<T, SchedulerErrors>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, SchedulerErrors> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || operation(&mut connection.lock())).await?
+    }
+
+    // Adds a chat id to either the subscribers or the shared table
+    async fn add_member(&self, table: &'static str, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        let (id, chat_id) = (id.to_string(), chat_id.to_string());
+
+        let event = self
+            .run(move |connection| {
+                let transaction = connection.transaction()?;
+                exists(&transaction, &id)?;
+                let inserted = transaction.execute(
+                    &format!(
+                        "INSERT INTO {} (job_id, chat_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING",
+                        table
+                    ),
+                    params![id, chat_id],
+                )?;
+                if inserted == 0 {
+                    return Ok(None);
+                }
+
+                bump_version(&transaction, &id)?;
+                let subscribers: i64 = transaction.query_row(
+                    "SELECT COUNT(*) FROM subscribers WHERE job_id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )?;
+                transaction.commit()?;
+
+                match (table, subscribers) {
+                    ("subscribers", 1) => Ok(Some(Event::Activated { id })),
+                    _ => Ok(Some(Event::Updated { id })),
+                }
+            })
+            .await?;
+
+        if let Some(event) = event {
+            self.events.send(event);
+        }
+
+        Ok(())
+    }
+
+    // Updates a single column of a job
+    async fn set_column<V>(&self, column: &'static str, id: &str, value: V) -> Result<(), SchedulerErrors>
+    where
+        V: rusqlite::ToSql + Send + 'static,
+    {
+        let id = id.to_string();
+        let event = Event::Updated { id: id.clone() };
+
+        self.run(move |connection| {
+            let query = format!("UPDATE jobs SET {} = ?1, version = version + 1 WHERE id = ?2", column);
+            match connection.execute(&query, params![value, id])? {
+                0 => Err(SchedulerErrors::NotFound(id)),
+                _ => Ok(()),
+            }
+        })
+        .await?;
+        self.events.send(event);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for SqlStore {
+    async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors> {
+        self.run(|connection| {
+            let transaction = connection.transaction()?;
+            let expired = expire(&transaction)?;
+
+            let mut records = HashMap::new();
+            {
+                let mut statement = transaction.prepare(&format!("SELECT {} FROM jobs", JOB_COLUMNS))?;
+                for record in statement.query_map(NO_PARAMS, record_from_row)? {
+                    let record = record?;
+                    records.insert(record.id.clone(), record);
+                }
+
+                for table in &["subscribers", "shared"] {
+                    let mut statement = transaction.prepare(&format!("SELECT job_id, chat_id FROM {}", table))?;
+                    let rows = statement.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+                    for row in rows {
+                        let (id, chat_id): (String, String) = row?;
+                        if let Some(record) = records.get_mut(&id) {
+                            match *table {
+                                "subscribers" => record.subscribers.insert(chat_id),
+                                _ => record.shared.insert(chat_id),
+                            };
+                        }
+                    }
+                }
+            }
+            transaction.commit()?;
+
+            Ok((records, expired))
+        })
+        .await
+        .map(|(records, expired)| {
+            for record in expired {
+                self.events.send(Event::Expired(Box::new(record)));
+            }
+
+            records
+        })
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors> {
+        let id = id.to_string();
+
+        self.run(move |connection| get(connection, &id)).await
+    }
+
+    async fn add(&self, record: Record) -> Result<(), SchedulerErrors> {
+        let event = self
+            .run(move |connection| {
+                let last_value = record.last_value.as_ref().map(|value| value.to_string());
+
+                let transaction = connection.transaction()?;
+                let previous = get(&transaction, &record.id)?;
+                transaction.execute(
+                    &format!(
+                        "INSERT INTO jobs ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                    ON CONFLICT (id) DO UPDATE SET url = excluded.url, interval = excluded.interval,
+                    script = excluded.script, last_value = excluded.last_value, owner = excluded.owner,
+                    ttl = excluded.ttl, expires_at = excluded.expires_at, version = excluded.version,
+                    activated = excluded.activated",
+                        JOB_COLUMNS
+                    ),
+                    params![
+                        record.id,
+                        record.url,
+                        record.interval as i64,
+                        record.script,
+                        last_value,
+                        record.owner,
+                        to_sql(record.ttl),
+                        to_sql(record.expires_at),
+                        record.version as i64,
+                        record.is_activated(),
+                    ],
+                )?;
+                set_members(&transaction, &record)?;
+                transaction.commit()?;
+
+                Ok(Event::changed(previous.as_ref(), &record))
+            })
+            .await?;
+        self.events.send(event);
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.add_member("subscribers", id, chat_id).await
+    }
+
+    async fn unsubscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        let (id, chat_id) = (id.to_string(), chat_id.to_string());
+        let event = Event::Updated { id: id.clone() };
+
+        let deleted = self
+            .run(move |connection| {
+                let transaction = connection.transaction()?;
+                let deleted = transaction.execute(
+                    "DELETE FROM subscribers WHERE job_id = ?1 AND chat_id = ?2",
+                    params![id, chat_id],
+                )?;
+                if deleted > 0 {
+                    bump_version(&transaction, &id)?;
+                }
+                transaction.commit()?;
+
+                Ok(deleted > 0)
+            })
+            .await?;
+
+        if deleted {
+            self.events.send(event);
+        }
+
+        Ok(())
+    }
+
+    async fn share(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors> {
+        self.add_member("shared", id, chat_id).await
+    }
+
+    async fn set_owner(&self, id: &str, owner: &str) -> Result<(), SchedulerErrors> {
+        self.set_column("owner", id, owner.to_string()).await
+    }
+
+    async fn renew(&self, id: &str, expires_at: Option<u64>) -> Result<(), SchedulerErrors> {
+        self.set_column("expires_at", id, to_sql(expires_at)).await
+    }
+
+    async fn add_code(&self, code: &str, id: &str, expires_at: u64) -> Result<(), SchedulerErrors> {
+        let (code, id) = (code.to_string(), id.to_string());
+
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO codes (code, job_id, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT (code) DO UPDATE SET job_id = excluded.job_id, expires_at = excluded.expires_at",
+                params![code, id, expires_at as i64],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn take_code(&self, code: &str) -> Result<Option<String>, SchedulerErrors> {
+        let code = code.to_string();
+
+        self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let id = transaction
+                .query_row(
+                    "SELECT job_id FROM codes WHERE code = ?1 AND expires_at > ?2",
+                    params![code, current_time() as i64],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            transaction.execute("DELETE FROM codes WHERE code = ?1", params![code])?;
+            transaction.commit()?;
+
+            Ok(id)
+        })
+        .await
+    }
+
+    async fn codes(&self) -> Result<Vec<Code>, SchedulerErrors> {
+        self.run(|connection| {
+            let mut statement =
+                connection.prepare("SELECT code, job_id, expires_at FROM codes WHERE expires_at > ?1")?;
+            let rows = statement.query_map(params![current_time() as i64], |row| {
+                Ok(Code {
+                    code: row.get(0)?,
+                    id: row.get(1)?,
+                    expires_at: row.get::<_, i64>(2)? as u64,
+                })
+            })?;
+
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        })
+        .await
+    }
+
+    async fn set_value(&self, id: &str, value: &serde_json::Value) -> Result<(), SchedulerErrors> {
+        let (id, value) = (id.to_string(), value.to_string());
+        let event = Event::Updated { id: id.clone() };
+
+        self.run(move |connection| {
+            match connection.execute(
+                "UPDATE jobs SET last_value = ?1, version = version + 1 WHERE id = ?2",
+                params![value, id],
+            )? {
+                0 => Err(SchedulerErrors::NotFound(id)),
+                _ => Ok(()),
+            }
+        })
+        .await?;
+        self.events.send(event);
+
+        Ok(())
+    }
+
+    async fn compare_and_set(&self, record: Record) -> Result<bool, SchedulerErrors> {
+        let event = Event::Updated { id: record.id.clone() };
+
+        let replaced = self
+            .run(move |connection| {
+                let last_value = record.last_value.as_ref().map(|value| value.to_string());
+
+                let transaction = connection.transaction()?;
+                let updated = transaction.execute(
+                    "UPDATE jobs SET url = ?1, interval = ?2, script = ?3, last_value = ?4, owner = ?5, ttl = ?6,
+                expires_at = ?7, activated = ?8, version = version + 1 WHERE id = ?9 AND version = ?10",
+                    params![
+                        record.url,
+                        record.interval as i64,
+                        record.script,
+                        last_value,
+                        record.owner,
+                        to_sql(record.ttl),
+                        to_sql(record.expires_at),
+                        record.is_activated(),
+                        record.id,
+                        record.version as i64,
+                    ],
+                )?;
+                if updated == 0 {
+                    exists(&transaction, &record.id)?;
+                    return Ok(false);
+                }
+                transaction.commit()?;
+
+                Ok(true)
+            })
+            .await?;
+
+        if replaced {
+            self.events.send(event);
+        }
+
+        Ok(replaced)
+    }
+
+    fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    async fn query(&self, query: &Query, cursor: Option<&str>, limit: usize) -> Result<Page, SchedulerErrors> {
+        let (query, cursor) = (query.clone(), cursor.map(String::from));
+
+        self.run(move |connection| {
+            let (conditions, mut parameters) = filter(&query, cursor.as_deref());
+            // One more than the limit, to know whether there's a next page
+            parameters.push(Box::new(limit.saturating_add(1).min(i64::MAX as usize) as i64));
+
+            let mut records = {
+                let sql = format!(
+                    "SELECT {} FROM jobs WHERE {} ORDER BY id LIMIT ?",
+                    JOB_COLUMNS, conditions
+                );
+                let mut statement = connection.prepare(&sql)?;
+                let rows =
+                    statement.query_map(parameters.iter().map(|parameter| parameter.as_ref()), record_from_row)?;
+                rows.collect::<rusqlite::Result<Vec<Record>>>()?
+            };
+            for record in records.iter_mut() {
+                record.subscribers = members(connection, "subscribers", &record.id)?;
+                record.shared = members(connection, "shared", &record.id)?;
+            }
+
+            Ok(Page::from_sorted(records, limit))
+        })
+        .await
+    }
+
+    async fn count(&self, query: &Query) -> Result<usize, SchedulerErrors> {
+        let query = query.clone();
+
+        self.run(move |connection| {
+            let (conditions, parameters) = filter(&query, None);
+            let count: i64 = connection.query_row(
+                &format!("SELECT COUNT(*) FROM jobs WHERE {}", conditions),
+                parameters.iter().map(|parameter| parameter.as_ref()),
+                |row| row.get(0),
+            )?;
+
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), SchedulerErrors> {
+        let id = id.to_string();
+        let event = Event::Deleted { id: id.clone() };
+
+        let deleted = self
+            .run(move |connection| {
+                let transaction = connection.transaction()?;
+                let deleted = remove(&transaction, &id)?;
+                transaction.commit()?;
+
+                Ok(deleted > 0)
+            })
+            .await?;
+
+        if deleted {
+            self.events.send(event);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::record;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    // A database with the schema of the first migrations only
+    fn create_database(path: &Path, version: usize) -> Connection {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch("CREATE TABLE schema_migrations (version BIGINT PRIMARY KEY)")
+            .unwrap();
+        for (index, migration) in MIGRATIONS[..version].iter().enumerate() {
+            connection.execute_batch(migration).unwrap();
+            connection
+                .execute(
+                    "INSERT INTO schema_migrations (version) VALUES (?1)",
+                    params![index as i64 + 1],
+                )
+                .unwrap();
+        }
+
+        connection
+    }
+
+    #[tokio::test]
+    async fn migrates_existing_database() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.db");
+        let connection = create_database(&path, 2);
+        for (id, owner) in &[("pending", None), ("activated", Some("chat"))] {
+            connection
+                .execute(
+                    "INSERT INTO jobs (id, url, interval, script, owner) VALUES (?1, 'https://example.com', 5, '', ?2)",
+                    params![id, owner],
+                )
+                .unwrap();
+        }
+        drop(connection);
+
+        let store = SqlStore::new(&path).await.unwrap();
+        assert!(!store.get("pending").await.unwrap().unwrap().is_activated());
+        assert!(store.get("activated").await.unwrap().unwrap().is_activated());
+        drop(store);
+
+        let connection = Connection::open(&path).unwrap();
+        let version: i64 = connection
+            .query_row("SELECT MAX(version) FROM schema_migrations", NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn reopens_migrated_database() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("records.db");
+
+        let store = SqlStore::new(&path).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        drop(store);
+
+        let store = SqlStore::new(&path).await.unwrap();
+        assert!(store.get("a").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn queries_by_status() {
+        let directory = TempDir::new().unwrap();
+        let store = SqlStore::new(directory.path().join("records.db")).await.unwrap();
+
+        let mut pending = record("a");
+        pending.activated = Some(false);
+        store.add(pending).await.unwrap();
+        store.add(record("b")).await.unwrap();
+        store.add(record("c")).await.unwrap();
+        store.subscribe("c", "chat").await.unwrap();
+
+        for (status, id) in &[(Status::Pending, "a"), (Status::Paused, "b"), (Status::Active, "c")] {
+            let query = Query {
+                status: Some(*status),
+                ..Query::default()
+            };
+            let page = store.query(&query, None, 10).await.unwrap();
+            let ids: Vec<&str> = page.records.iter().map(|record| record.id.as_str()).collect();
+            assert_eq!(ids, vec![*id]);
+            assert_eq!(store.count(&query).await.unwrap(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_records() {
+        let directory = TempDir::new().unwrap();
+        let store = SqlStore::new(directory.path().join("records.db")).await.unwrap();
+        store.add(record("a")).await.unwrap();
+        store.share("a", "friend").await.unwrap();
+        store.set_value("a", &json!([1, 2])).await.unwrap();
+        store.add_code("code", "a", current_time() + 60).await.unwrap();
+
+        let stored = store.get("a").await.unwrap().unwrap();
+        assert!(stored.shared.contains("friend"));
+        assert_eq!(stored.last_value, Some(json!([1, 2])));
+        assert_eq!(store.take_code("code").await.unwrap(), Some(String::from("a")));
+        assert_eq!(store.take_code("code").await.unwrap(), None);
+
+        store.delete("a").await.unwrap();
+        assert!(store.get("a").await.unwrap().is_none());
+    }
+}
@@ -1,7 +1,20 @@
 use crate::SchedulerErrors;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+// Number of events a listener can fall behind before it starts missing them
+const EVENTS_CAPACITY: usize = 1_024;
+// How often the stores look for expired records
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn current_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
@@ -9,7 +22,184 @@ pub struct Record {
     pub interval: u64,
     pub script: String,
     pub url: String,
+    // Chat ids that receive the notifications of this record
+    pub subscribers: HashSet<String>,
+    // Result of the last run of the script. used to detect changes between runs
+    pub last_value: Option<serde_json::Value>,
+    // Either the creator token passed by the api or the chat id of the telegram user that claimed
+    // the record. only the owner can delete, share or transfer it
+    pub owner: Option<String>,
+    // Chat ids the owner allowed to subscribe. they can't modify the record
+    pub shared: HashSet<String>,
+    // How long the record lives after it's created or renewed. None means that it never expires
+    pub ttl: Option<u64>,
+    // Unix timestamp in seconds
+    pub expires_at: Option<u64>,
+    // Incremented on every change. used by compare_and_set to detect concurrent modifications
+    #[serde(default)]
+    pub version: u64,
+    // Set once the record is activated with its code. None for records stored before the flag
+    // existed, which were activated once they had an owner
+    #[serde(default)]
+    pub activated: Option<bool>,
+}
+
+impl Record {
+    pub fn is_owner(&self, chat_id: &str) -> bool {
+        self.owner.as_deref() == Some(chat_id)
+    }
+
+    pub fn can_subscribe(&self, chat_id: &str) -> bool {
+        self.is_owner(chat_id) || self.shared.contains(chat_id)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= current_time())
+    }
+
+    pub fn is_activated(&self) -> bool {
+        self.activated.unwrap_or_else(|| self.owner.is_some())
+    }
+
+    pub fn status(&self) -> Status {
+        if !self.is_activated() {
+            Status::Pending
+        } else if self.subscribers.is_empty() {
+            Status::Paused
+        } else {
+            Status::Active
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    // Created but not activated yet
+    Pending,
+    // Activated and has at least one subscriber
+    Active,
+    // Activated but nobody is subscribed, so it doesn't run
+    Paused,
+}
+
+/// A change of a record in the store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    Created { id: String },
+    // The record got its first subscriber, so it's scheduled
+    Activated { id: String },
+    Updated { id: String },
+    Deleted { id: String },
+    // The record as it was when it expired, so its subscribers can still be told about it
+    Expired(Box<Record>),
+}
+
+impl Event {
+    // The event of a change from the previous state of a record to the current one
+    pub fn changed(previous: Option<&Record>, current: &Record) -> Self {
+        let id = current.id.clone();
+
+        match previous {
+            None => Event::Created { id },
+            Some(previous) if previous.subscribers.is_empty() && !current.subscribers.is_empty() => {
+                Event::Activated { id }
+            }
+            Some(_) => Event::Updated { id },
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Event::Created { id } | Event::Activated { id } | Event::Updated { id } | Event::Deleted { id } => id,
+            Event::Expired(record) => &record.id,
+        }
+    }
+}
+
+/// Sends the events of a store to everyone listening to it
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    pub fn send(&self, event: Event) {
+        // Nobody might be listening, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+
+        Events { sender }
+    }
+}
+
+/// An activation code that wasn't used yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Code {
+    pub code: String,
+    // The record it activates
+    pub id: String,
+    // Unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+/// Filters of Store::query. Empty filters match every record
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    // Records this chat is subscribed to
     pub chat_id: Option<String>,
+    pub owner: Option<String>,
+    pub status: Option<Status>,
+}
+
+impl Query {
+    pub fn matches(&self, record: &Record) -> bool {
+        let chat_id = match &self.chat_id {
+            Some(chat_id) => record.subscribers.contains(chat_id),
+            None => true,
+        };
+        let owner = match &self.owner {
+            Some(owner) => record.is_owner(owner),
+            None => true,
+        };
+        let status = match self.status {
+            Some(status) => record.status() == status,
+            None => true,
+        };
+
+        chat_id && owner && status
+    }
+}
+
+/// Records ordered by id. `cursor` is passed to the next query to get the following page, and is
+/// None on the last one
+#[derive(Debug)]
+pub struct Page {
+    pub records: Vec<Record>,
+    pub cursor: Option<String>,
+}
+
+impl Page {
+    // Takes a page out of records already sorted by id and past the cursor
+    pub fn from_sorted(mut records: Vec<Record>, limit: usize) -> Self {
+        let cursor = if records.len() > limit {
+            records.truncate(limit);
+            records.last().map(|record| record.id.clone())
+        } else {
+            None
+        };
+
+        Page { records, cursor }
+    }
 }
 
 #[async_trait]
@@ -17,6 +207,91 @@ pub trait Store {
     async fn load(&self) -> Result<HashMap<String, Record>, SchedulerErrors>;
     async fn get(&self, id: &str) -> Result<Option<Record>, SchedulerErrors>;
     async fn add(&self, record: Record) -> Result<(), SchedulerErrors>;
-    async fn update(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors>;
+    async fn subscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors>;
+    async fn unsubscribe(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors>;
+    async fn share(&self, id: &str, chat_id: &str) -> Result<(), SchedulerErrors>;
+    async fn set_owner(&self, id: &str, owner: &str) -> Result<(), SchedulerErrors>;
+    async fn renew(&self, id: &str, expires_at: Option<u64>) -> Result<(), SchedulerErrors>;
+    // Activation codes map to the id of the record they activate, and can be used only once
+    async fn add_code(&self, code: &str, id: &str, expires_at: u64) -> Result<(), SchedulerErrors>;
+    async fn take_code(&self, code: &str) -> Result<Option<String>, SchedulerErrors>;
+    // The codes that weren't used and haven't expired
+    async fn codes(&self) -> Result<Vec<Code>, SchedulerErrors>;
+    async fn set_value(&self, id: &str, value: &serde_json::Value) -> Result<(), SchedulerErrors>;
     async fn delete(&self, id: &str) -> Result<(), SchedulerErrors>;
+    // Replaces the record only if its version is still the one it was read with. Returns false
+    // when someone else changed it in the meantime. subscribers and shared are changed only
+    // through subscribe, unsubscribe and share, so they are left as they are
+    async fn compare_and_set(&self, record: Record) -> Result<bool, SchedulerErrors>;
+    // Changes of the records from now on. stores shared between processes also report the changes
+    // made by the others
+    fn events(&self) -> broadcast::Receiver<Event>;
+
+    // Records matching the query with an id greater than the cursor. stores without indexes
+    // filter everything in memory
+    async fn query(&self, query: &Query, cursor: Option<&str>, limit: usize) -> Result<Page, SchedulerErrors> {
+        let records = self.load().await?;
+        let mut records: Vec<Record> = records
+            .values()
+            .filter(|record| query.matches(record))
+            .filter(|record| !matches!(cursor, Some(cursor) if record.id.as_str() <= cursor))
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(Page::from_sorted(records, limit))
+    }
+
+    async fn count(&self, query: &Query) -> Result<usize, SchedulerErrors> {
+        Ok(self.query(query, None, usize::MAX).await?.records.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // An activated record that never expires
+    pub fn record(id: &str) -> Record {
+        Record {
+            id: id.to_string(),
+            interval: 5,
+            script: String::from("return document.title"),
+            url: String::from("https://example.com"),
+            subscribers: HashSet::new(),
+            last_value: None,
+            owner: Some(String::from("owner")),
+            shared: HashSet::new(),
+            ttl: None,
+            expires_at: None,
+            version: 0,
+            activated: Some(true),
+        }
+    }
+
+    #[test]
+    fn status() {
+        let mut record = record("a");
+        assert_eq!(record.status(), Status::Paused);
+
+        record.subscribers.insert(String::from("chat"));
+        assert_eq!(record.status(), Status::Active);
+
+        // Jobs of the api have an owner before they're activated
+        record.activated = Some(false);
+        assert_eq!(record.status(), Status::Pending);
+    }
+
+    #[test]
+    fn legacy_records_activated_by_owner() {
+        let mut stored = serde_json::to_value(record("a")).unwrap();
+        stored.as_object_mut().unwrap().remove("activated");
+
+        let legacy: Record = serde_json::from_value(stored.clone()).unwrap();
+        assert!(legacy.is_activated());
+
+        stored["owner"] = serde_json::Value::Null;
+        let legacy: Record = serde_json::from_value(stored).unwrap();
+        assert_eq!(legacy.status(), Status::Pending);
+    }
 }
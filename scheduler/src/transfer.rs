@@ -0,0 +1,259 @@
+use crate::fs_store::FileStore;
+use crate::redis_store::{RedisStore, DEFAULT_NAMESPACE};
+use crate::sql_store::SqlStore;
+use crate::store::{current_time, Code, Query, Record, Store};
+use crate::SchedulerErrors;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+// Records are read a page at a time, so a large store isn't loaded into memory at once
+const PAGE_SIZE: usize = 100;
+
+/// A line of an export. Codes are told apart from records by their fields, so exports made before
+/// they were included can still be imported
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Code(Code),
+    Record(Box<Record>),
+}
+
+/// Opens the store described by `spec`. `file:<path>` and `sqlite:<path>` open the embedded
+/// stores, anything else is treated as a Redis url, optionally followed by `#<namespace>`
+pub async fn open(spec: &str) -> Result<Box<dyn Store + Send + Sync>, SchedulerErrors> {
+    if let Some(path) = spec.strip_prefix("file:") {
+        Ok(Box::new(FileStore::new(path).await?))
+    } else if let Some(path) = spec.strip_prefix("sqlite:") {
+        Ok(Box::new(SqlStore::new(path).await?))
+    } else {
+        let mut parts = spec.splitn(2, '#');
+        let addr = parts.next().unwrap_or_default();
+        let namespace = parts.next().unwrap_or(DEFAULT_NAMESPACE);
+
+        Ok(Box::new(RedisStore::new(addr, namespace).await?))
+    }
+}
+
+// The codes of the store by the id of their record. only records that weren't activated have
+// codes, so there are few of them
+async fn codes_by_id<S>(store: &S) -> Result<HashMap<String, Vec<Code>>, SchedulerErrors>
+where
+    S: Store + ?Sized,
+{
+    let mut codes: HashMap<String, Vec<Code>> = HashMap::new();
+    for code in store.codes().await? {
+        codes.entry(code.id.clone()).or_default().push(code);
+    }
+
+    Ok(codes)
+}
+
+fn write_line<W>(writer: &mut W, line: &Line) -> Result<(), SchedulerErrors>
+where
+    W: Write,
+{
+    serde_json::to_writer(&mut *writer, line)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Writes every record of the store as a line of JSON, ordered by id, followed by the activation
+/// codes of the records that weren't activated yet. Returns the number of records written
+pub async fn export<S, W>(store: &S, mut writer: W) -> Result<usize, SchedulerErrors>
+where
+    S: Store + Sync + ?Sized,
+    W: Write,
+{
+    let mut codes = codes_by_id(store).await?;
+    let mut exported_codes = Vec::new();
+    let mut count = 0;
+    let mut cursor = None;
+
+    loop {
+        let page = store.query(&Query::default(), cursor.as_deref(), PAGE_SIZE).await?;
+        for record in page.records {
+            exported_codes.extend(codes.remove(&record.id).unwrap_or_default());
+            write_line(&mut writer, &Line::Record(Box::new(record)))?;
+            count += 1;
+        }
+
+        cursor = match page.cursor {
+            Some(next) => Some(next),
+            None => break,
+        };
+    }
+
+    exported_codes.sort_by(|a, b| a.code.cmp(&b.code));
+    for code in exported_codes {
+        write_line(&mut writer, &Line::Code(code))?;
+    }
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// Adds the records and codes of an export to the store, keeping their ids, subscribers and
+/// expiry. Records and codes that expired since the export are skipped. Returns the number of
+/// records added
+pub async fn import<S, R>(store: &S, reader: R) -> Result<usize, SchedulerErrors>
+where
+    S: Store + ?Sized,
+    R: BufRead,
+{
+    let now = current_time();
+    let mut imported = 0;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str(&line)? {
+            Line::Record(record) if matches!(record.expires_at, Some(expires_at) if expires_at <= now) => {
+                info!("Skipping expired record {}", record.id);
+            }
+            Line::Record(record) => {
+                store.add(*record).await?;
+                imported += 1;
+            }
+            Line::Code(code) if code.expires_at <= now => {}
+            Line::Code(code) => store.add_code(&code.code, &code.id, code.expires_at).await?,
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Copies every record, and the codes of the ones that weren't activated, from one store to another
+pub async fn copy<S, D>(source: &S, destination: &D) -> Result<usize, SchedulerErrors>
+where
+    S: Store + Sync + ?Sized,
+    D: Store + ?Sized,
+{
+    let mut codes = codes_by_id(source).await?;
+    let mut count = 0;
+    let mut cursor = None;
+
+    loop {
+        let page = source.query(&Query::default(), cursor.as_deref(), PAGE_SIZE).await?;
+        for record in page.records {
+            for code in codes.remove(&record.id).unwrap_or_default() {
+                destination.add_code(&code.code, &code.id, code.expires_at).await?;
+            }
+            destination.add(record).await?;
+            count += 1;
+        }
+
+        cursor = match page.cursor {
+            Some(next) => Some(next),
+            None => break,
+        };
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::record;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    async fn file_store(directory: &TempDir, name: &str) -> FileStore {
+        FileStore::new(directory.path().join(name)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let directory = TempDir::new().unwrap();
+        let source = file_store(&directory, "source.jsonl").await;
+        let mut active = record("a");
+        active.subscribers.insert(String::from("chat"));
+        active.last_value = Some(json!({"price": 10}));
+        source.add(active).await.unwrap();
+        let mut pending = record("b");
+        pending.activated = Some(false);
+        pending.expires_at = Some(current_time() + 60);
+        source.add(pending).await.unwrap();
+        source.add_code("code", "b", current_time() + 60).await.unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&source, &mut exported).await.unwrap(), 2);
+
+        let destination = SqlStore::new(directory.path().join("destination.db")).await.unwrap();
+        assert_eq!(import(&destination, &exported[..]).await.unwrap(), 2);
+
+        let a = destination.get("a").await.unwrap().unwrap();
+        assert!(a.subscribers.contains("chat"));
+        assert_eq!(a.last_value, Some(json!({"price": 10})));
+        assert!(!destination.get("b").await.unwrap().unwrap().is_activated());
+        assert_eq!(destination.take_code("code").await.unwrap(), Some(String::from("b")));
+    }
+
+    #[tokio::test]
+    async fn exports_every_page() {
+        let directory = TempDir::new().unwrap();
+        let store = SqlStore::new(directory.path().join("records.db")).await.unwrap();
+        let count = PAGE_SIZE * 2 + 1;
+        for index in 0..count {
+            store.add(record(&format!("{:03}", index))).await.unwrap();
+        }
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&store, &mut exported).await.unwrap(), count);
+
+        let ids: Vec<String> = exported
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<Record>(line).unwrap().id)
+            .collect();
+        let expected: Vec<String> = (0..count).map(|index| format!("{:03}", index)).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn import_skips_expired() {
+        let directory = TempDir::new().unwrap();
+        let mut expired = record("a");
+        expired.expires_at = Some(current_time() - 1);
+        let code = Code {
+            code: String::from("code"),
+            id: String::from("b"),
+            expires_at: current_time() - 1,
+        };
+        let lines = [
+            serde_json::to_string(&expired).unwrap(),
+            serde_json::to_string(&record("b")).unwrap(),
+            serde_json::to_string(&code).unwrap(),
+        ];
+
+        let store = file_store(&directory, "records.jsonl").await;
+        assert_eq!(import(&store, lines.join("\n").as_bytes()).await.unwrap(), 1);
+        assert!(store.get("b").await.unwrap().is_some());
+        assert!(store.codes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn copies_codes() {
+        let directory = TempDir::new().unwrap();
+        let source = file_store(&directory, "source.jsonl").await;
+        source.add(record("a")).await.unwrap();
+        source.add_code("code", "a", current_time() + 60).await.unwrap();
+        // A code of a record that's gone
+        source.add_code("other", "b", current_time() + 60).await.unwrap();
+
+        let destination = file_store(&directory, "destination.jsonl").await;
+        assert_eq!(copy(&source, &destination).await.unwrap(), 1);
+
+        let codes = destination.codes().await.unwrap();
+        assert_eq!(codes.len(), 1);
+        assert_eq!(codes[0].code, "code");
+    }
+}
@@ -1,12 +1,11 @@
 import amqp, { ConsumeMessage } from 'amqplib';
 import type { Channel, Connection } from 'amqplib';
 
-export type Exchanges = 'scraper' | 'bot';
+export type Exchanges = 'scraper' | 'scheduler' | 'api';
 
 export interface Scrape {
   Scrape: {
     id: string;
-    chat_id: string;
     url: string;
     script: string;
   };
@@ -16,21 +15,47 @@ export function isScrape(msg: any): msg is Scrape {
   const obj = JSON.parse(msg);
 
   if (obj.Scrape) {
-    return ['id', 'chat_id', 'url', 'script'].every((prop) => prop in obj.Scrape);
+    return ['id', 'url', 'script'].every((prop) => prop in obj.Scrape);
   }
 
   return false;
 }
 
-export interface Notify {
-  Notify: {
+export interface ScrapeResponse {
+  ScrapeResponse: {
     id: string;
-    chat_id: string;
+    value: unknown;
+  };
+}
+
+// Runs the script once without creating a job, to test it
+export interface TestScrape {
+  TestScrape: {
+    request_id: string;
     url: string;
+    script: string;
+  };
+}
+
+export function isTestScrape(msg: any): msg is TestScrape {
+  const obj = JSON.parse(msg);
+
+  if (obj.TestScrape) {
+    return ['request_id', 'url', 'script'].every((prop) => prop in obj.TestScrape);
+  }
+
+  return false;
+}
+
+export interface TestScrapeResponse {
+  TestScrapeResponse: {
+    request_id: string;
+    // What the script returned, or why it failed
+    result: { Ok: unknown } | { Err: string };
   };
 }
 
-export type Messages = Scrape | Notify;
+export type Messages = Scrape | ScrapeResponse | TestScrape | TestScrapeResponse;
 
 export interface Consumer {
   (msg: ConsumeMessage | null): void;
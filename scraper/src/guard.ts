@@ -0,0 +1,209 @@
+import dns from 'dns';
+import http from 'http';
+import https from 'https';
+import net from 'net';
+import type { Request, Route } from 'playwright';
+
+// Responses are read into memory before they are passed to the page
+const MAX_BODY = 10 * 1024 * 1024;
+const REQUEST_TIMEOUT = 30_000;
+// Set by node itself or describe a body that is passed decoded
+const DROPPED_HEADERS = ['connection', 'content-encoding', 'content-length', 'keep-alive', 'transfer-encoding'];
+
+function isPublicV4(address: string): boolean {
+  const [a, b, c] = address.split('.').map(Number);
+
+  // 0.0.0.0/8, 10.0.0.0/8, 127.0.0.0/8, and multicast and reserved from 224.0.0.0 up
+  if (a === 0 || a === 10 || a === 127 || a >= 224) {
+    return false;
+  }
+  // 100.64.0.0/10 (carrier grade NAT), 169.254.0.0/16 (link local), 172.16.0.0/12 and 192.168.0.0/16
+  if ((a === 100 && (b & 0xc0) === 64) || (a === 169 && b === 254) || (a === 172 && (b & 0xf0) === 16)) {
+    return false;
+  }
+  if (a === 192 && b === 168) {
+    return false;
+  }
+  // 192.0.0.0/24 and the documentation ranges
+  return !(
+    (a === 192 && b === 0 && (c === 0 || c === 2)) ||
+    (a === 198 && b === 51 && c === 100) ||
+    (a === 203 && b === 0 && c === 113)
+  );
+}
+
+// The 8 segments of an IPv6 address. an IPv4 address at the end takes the last two
+function segmentsV6(address: string): number[] {
+  let text = address.split('%')[0];
+  const dot = text.lastIndexOf('.');
+  if (dot !== -1) {
+    const start = text.lastIndexOf(':') + 1;
+    const [a, b, c, d] = text.slice(start).split('.').map(Number);
+    text = `${text.slice(0, start)}${((a << 8) | b).toString(16)}:${((c << 8) | d).toString(16)}`;
+  }
+
+  const [head, tail] = text.split('::');
+  const parse = (part?: string) => (part ? part.split(':').map((segment) => parseInt(segment, 16)) : []);
+  const start = parse(head);
+  const end = parse(tail);
+  const zeros = tail === undefined ? [] : new Array(8 - start.length - end.length).fill(0);
+
+  return [...start, ...zeros, ...end];
+}
+
+function isPublicV6(address: string): boolean {
+  const segments = segmentsV6(address);
+
+  // IPv4 mapped (::ffff:0:0/96) and compatible addresses reach the IPv4 address they contain
+  if (segments.slice(0, 5).every((segment) => segment === 0) && (segments[5] === 0 || segments[5] === 0xffff)) {
+    if (segments[5] === 0 && segments[6] === 0) {
+      // :: and ::1
+      return false;
+    }
+    const octets = [segments[6] >> 8, segments[6] & 0xff, segments[7] >> 8, segments[7] & 0xff];
+    return isPublicV4(octets.join('.'));
+  }
+
+  // Multicast, fc00::/7 (unique local) and fe80::/10 (link local)
+  return !(
+    (segments[0] & 0xff00) === 0xff00 ||
+    (segments[0] & 0xfe00) === 0xfc00 ||
+    (segments[0] & 0xffc0) === 0xfe80
+  );
+}
+
+// The same rule the api applies to the URLs of new jobs
+export function isPublic(address: string): boolean {
+  switch (net.isIP(address)) {
+    case 4:
+      return isPublicV4(address);
+    case 6:
+      return isPublicV6(address);
+    default:
+      return false;
+  }
+}
+
+// Resolves the host like dns.lookup, but fails unless all of its addresses are public. the request is
+// made to the address that was checked, so the host can't resolve to another one in between
+const checkedLookup: net.LookupFunction = (hostname, options, callback) => {
+  dns.lookup(hostname, { all: true }, (error, addresses) => {
+    if (error) {
+      callback(error, '', 0);
+      return;
+    }
+
+    const blocked = addresses.find(({ address }) => !isPublic(address));
+    if (blocked || addresses.length === 0) {
+      const reason = new Error(`${hostname} resolves to ${blocked?.address ?? 'nothing'}, which is not public`);
+      callback(reason as NodeJS.ErrnoException, '', 0);
+      return;
+    }
+
+    const [{ address, family }] = addresses;
+    // Newer versions of node ask for all the addresses
+    if ((options as { all?: boolean }).all) {
+      (callback as unknown as (error: null, addresses: dns.LookupAddress[]) => void)(null, [{ address, family }]);
+    } else {
+      callback(null, address, family);
+    }
+  });
+};
+
+interface Fetched {
+  status: number;
+  headers: Record<string, string>;
+  body: Buffer;
+}
+
+// Makes the request of the page. redirects aren't followed, the page follows them with a request of its own
+function fetch(request: Request): Promise<Fetched> {
+  const url = new URL(request.url());
+  const client = url.protocol === 'https:' ? https : http;
+  const headers = { ...request.headers(), 'accept-encoding': 'identity' };
+
+  return new Promise((resolve, reject) => {
+    const outgoing = client.request(
+      url,
+      { method: request.method(), headers, lookup: checkedLookup, timeout: REQUEST_TIMEOUT },
+      (response) => {
+        const chunks: Buffer[] = [];
+        let length = 0;
+
+        response.on('data', (chunk: Buffer) => {
+          length += chunk.length;
+          if (length > MAX_BODY) {
+            response.destroy(new Error(`Response of ${url.href} is larger than ${MAX_BODY} bytes`));
+            return;
+          }
+          chunks.push(chunk);
+        });
+        response.on('error', reject);
+        response.on('end', () => {
+          const fetchedHeaders: Record<string, string> = {};
+          for (const [name, value] of Object.entries(response.headers)) {
+            if (value !== undefined && !DROPPED_HEADERS.includes(name)) {
+              fetchedHeaders[name] = Array.isArray(value) ? value.join('\n') : value;
+            }
+          }
+
+          resolve({ status: response.statusCode ?? 502, headers: fetchedHeaders, body: Buffer.concat(chunks) });
+        });
+      },
+    );
+
+    outgoing.on('timeout', () => outgoing.destroy(new Error(`Request to ${url.href} timed out`)));
+    outgoing.on('error', reject);
+    outgoing.end(request.postDataBuffer() ?? undefined);
+  });
+}
+
+/**
+ * Makes the requests of a page on its behalf, so that none of them, redirects included, reaches a
+ * private address. Playwright only routes the first request of a redirect chain, so the guard fetches
+ * every request itself and hands redirects back to the page, which routes the next hop again
+ */
+class Guard {
+  // Why the last navigation failed, which the error of the page itself doesn't tell
+  failure?: string;
+  private closed = false;
+
+  async handle(route: Route): Promise<void> {
+    const request = route.request();
+    if (this.closed) {
+      await route.abort();
+      return;
+    }
+
+    const { protocol } = new URL(request.url());
+    if (protocol !== 'http:' && protocol !== 'https:') {
+      await this.block(route, `${protocol} URLs are not allowed`);
+      return;
+    }
+
+    let fetched: Fetched;
+    try {
+      fetched = await fetch(request);
+    } catch (error) {
+      await this.block(route, `${error.message ?? error}`);
+      return;
+    }
+
+    await route.fulfill(fetched);
+  }
+
+  // Aborts every request from now on
+  close(): void {
+    this.closed = true;
+  }
+
+  private async block(route: Route, reason: string): Promise<void> {
+    if (route.request().isNavigationRequest()) {
+      this.failure = reason;
+    }
+
+    await route.abort('blockedbyclient');
+  }
+}
+
+export default Guard;
@@ -1,7 +1,7 @@
 import winston from 'winston';
-import Broker, { isScrape } from './broker';
+import Broker, { isScrape, isTestScrape } from './broker';
 import Scraper from './scraper';
-import type { Scrape, Notify } from './broker';
+import type { Scrape, ScrapeResponse, TestScrape, TestScrapeResponse } from './broker';
 
 const logger = winston.createLogger({
   level: 'info',
@@ -33,21 +33,41 @@ const logger = winston.createLogger({
 
       if (isScrape(content)) {
         const message: Scrape = JSON.parse(content);
-        const isSuccess = await scraper.run(message.Scrape.url, message.Scrape.script);
-
-        if (isSuccess) {
-          logger.info(`success. send message`);
-          const brokerMsg: Notify = {
-            Notify: {
+        try {
+          const value = await scraper.run(message.Scrape.url, message.Scrape.script);
+          // The scheduler decides whether the value is worth a notification
+          const brokerMsg: ScrapeResponse = {
+            ScrapeResponse: {
               id: message.Scrape.id,
-              chat_id: message.Scrape.chat_id,
-              url: message.Scrape.url,
+              value,
             },
           };
 
-          await broker.publish('bot', brokerMsg);
-        } else {
-          logger.info(`Failure in scraper. message: ${message}`);
+          await broker.publish('scheduler', brokerMsg);
+        } catch (error) {
+          logger.info(`Failure in scraper. message: ${message}. error: ${error}`);
+        }
+      } else if (isTestScrape(content)) {
+        const message: TestScrape = JSON.parse(content);
+        let result: TestScrapeResponse['TestScrapeResponse']['result'];
+        try {
+          result = { Ok: await scraper.run(message.TestScrape.url, message.TestScrape.script) };
+        } catch (error) {
+          // Sent back to the user, who is testing the script to find out about these
+          result = { Err: `${error.message ?? error}` };
+        }
+
+        const brokerMsg: TestScrapeResponse = {
+          TestScrapeResponse: {
+            request_id: message.TestScrape.request_id,
+            result,
+          },
+        };
+
+        try {
+          await broker.publish('api', brokerMsg);
+        } catch (error) {
+          logger.warn(`Failure in scraper. message: ${message}. error: ${error}`);
         }
       }
     });
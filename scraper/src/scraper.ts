@@ -1,22 +1,34 @@
 import { chromium } from 'playwright';
+import Guard from './guard';
 
 class Scraper {
-  async run(url: string, script: string): Promise<boolean> {
+  // Returns whatever the script returned. it has to be JSON serializable, undefined becomes null
+  async run(url: string, script: string): Promise<unknown> {
     const browser = await chromium.launch();
-    const page = await browser.newPage();
-    await page.goto(url);
+    // Scripts that fail, which test scrapes run into, mustn't leave the browser open
+    try {
+      const page = await browser.newPage();
+      // The page can't reach private addresses, neither by its URL nor by redirects
+      const guard = new Guard();
+      await page.route('**/*', (route) => guard.handle(route));
+      try {
+        await page.goto(url);
+      } catch (error) {
+        // Test scrapes send the error back. net::ERR_BLOCKED_BY_CLIENT doesn't tell what was wrong
+        throw guard.failure ? new Error(`${url} can't be loaded. ${guard.failure}`) : error;
+      }
 
-    // After page load, disable all network requests
-    await page.route('**/*', (route) => {
-      route.abort();
-    });
-    const fullScript = `(() => {
-      ${script}
-    })();`;
-    const result: boolean = (await page.evaluate(fullScript)) || false;
-    await browser.close();
+      // After page load, disable all network requests
+      guard.close();
+      const fullScript = `(() => {
+        ${script}
+      })();`;
+      const result: unknown = await page.evaluate(fullScript);
 
-    return result;
+      return result === undefined ? null : result;
+    } finally {
+      await browser.close();
+    }
   }
 }
 